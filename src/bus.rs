@@ -1,46 +1,235 @@
-use crate::{cpu::Memory, cartridge::Rom, ppu::ppu::PPU, input::joypad::Joypad};
+use crate::{cpu::Memory, cartridge::{Rom, TvSystem}, ppu::ppu::PPU, input::joypad::Joypad};
 
 pub struct Bus<'call> {
     cpu_vram: [u8; 2048],
     prg_rom: Vec<u8>,
     ppu: PPU,
     joypad: Joypad,
+    joypad2: Joypad,
 
     pub cycles: usize,
     gameloop_callback: Box<dyn FnMut(&PPU, &mut Joypad) + 'call>,
+
+    // (address, was_write) for accesses nothing on the bus handles
+    access_warnings: Vec<(u16, bool)>,
+    pub log_unknown_access: bool,
+
+    // Address being watched and the last value the CPU wrote to it
+    write_watch: Option<u16>,
+    watch_hit: Option<u8>,
+
+    // OAMDMA was written, the stall starts once the writing instruction finishes
+    dma_pending: bool,
+    // CPU cycles left before an OAM DMA releases the CPU
+    dma_stall: usize,
+
+    // Last write into cartridge ROM space, strict CPUs report it as an error
+    rom_write: Option<u16>,
+
+    // Plain 64K of RAM in place of the NES memory map, for running bare 6502 code
+    flat_memory: Option<Box<[u8; 0x10000]>>,
 }
 
+const MAX_ACCESS_WARNINGS: usize = 256;
+
 impl<'a> Bus<'a> {
     pub fn new<'call, F>(rom: Rom, gameloop_callback: F) -> Bus<'call> 
     where F: FnMut(&PPU, &mut Joypad) + 'call,
     {
-        let ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+        let ppu = PPU::new(rom.chr_rom, rom.header.mirroring);
+        Bus::with_ppu(rom.prg_rom, ppu, gameloop_callback)
+    }
+
+    // Same as new but OAM powers up filled with pattern instead of zeros, see
+    // PPU::new_with_oam_pattern
+    pub fn new_with_oam_pattern<'call, F>(rom: Rom, pattern: u8, gameloop_callback: F) -> Bus<'call>
+    where F: FnMut(&PPU, &mut Joypad) + 'call,
+    {
+        let ppu = PPU::new_with_oam_pattern(rom.chr_rom, rom.header.mirroring, pattern);
+        Bus::with_ppu(rom.prg_rom, ppu, gameloop_callback)
+    }
+
+    // No cartridge or memory mapped registers, every address reads and writes the image
+    pub fn new_flat<'call>(image: &[u8; 0x10000]) -> Bus<'call> {
+        let mut bus = Bus::with_ppu(vec![], PPU::new_empty_rom(), |_, _|{});
+        bus.flat_memory = Some(Box::new(*image));
+        bus
+    }
+
+    fn with_ppu<'call, F>(prg_rom: Vec<u8>, ppu: PPU, gameloop_callback: F) -> Bus<'call>
+    where F: FnMut(&PPU, &mut Joypad) + 'call,
+    {
         let joypad = Joypad::new();
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            prg_rom,
             ppu,
             joypad,
+            joypad2: Joypad::new(),
             cycles: 0,
             gameloop_callback: Box::from(gameloop_callback),
+            access_warnings: vec![],
+            log_unknown_access: false,
+            write_watch: None,
+            watch_hit: None,
+            dma_pending: false,
+            dma_stall: 0,
+            rom_write: None,
+            flat_memory: None,
         }
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            // Mirror
-            addr = addr % 0x4000;
+    // Swap the per frame callback, e.g. when toggling a debug view
+    pub fn set_gameloop_callback<F>(&mut self, gameloop_callback: F)
+    where F: FnMut(&PPU, &mut Joypad) + 'a,
+    {
+        self.gameloop_callback = Box::from(gameloop_callback);
+    }
+
+    fn read_prg_rom(&self, addr: u16) -> u8 {
+        // NROM has no banking, so mask to the PRG size. This mirrors 16K carts into 0xC000
+        // and keeps bigger (mapped) PRG from indexing out of bounds
+        let addr = (addr - 0x8000) as usize % self.prg_rom.len();
+        self.prg_rom[addr]
+    }
+
+    fn unknown_access(&mut self, addr: u16, write: bool) {
+        if self.log_unknown_access {
+            println!("Unknown memory access at {:X}", addr);
+        }
+        if self.access_warnings.len() < MAX_ACCESS_WARNINGS {
+            self.access_warnings.push((addr, write));
         }
-        self.prg_rom[addr as usize]
+    }
+
+    // Watches match through mirrors, a watch on $0010 also catches writes to $0810
+    pub fn set_write_watch(&mut self, addr: Option<u16>) {
+        self.write_watch = addr.map(|addr| self.watch_addr(addr));
+        self.watch_hit = None;
+    }
+
+    // Flat memory has no mirrors to fold
+    fn watch_addr(&self, addr: u16) -> u16 {
+        match self.flat_memory {
+            Some(_) => addr,
+            None => canonical_addr(addr),
+        }
+    }
+
+    // Value written to the watched address since the last call, if any
+    pub fn take_watch_hit(&mut self) -> Option<u8> {
+        self.watch_hit.take()
+    }
+
+    pub fn take_rom_write(&mut self) -> Option<u16> {
+        self.rom_write.take()
+    }
+
+    pub fn take_access_warnings(&mut self) -> Vec<(u16, bool)> {
+        std::mem::take(&mut self.access_warnings)
     }
 
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         return self.ppu.nmi_interrupt.take();
     }
 
+    // Read without side effects for debuggers, matching what mem_read would return.
+    // Registers that can't be read without changing state (PPUDATA, the joypad) come back as 0
+    pub fn peek(&self, addr: u16) -> u8 {
+        if let Some(memory) = &self.flat_memory {
+            return memory[addr as usize];
+        }
+        match addr {
+            RAM ..= RAM_MIRRORS_END => self.cpu_vram[(addr & 0b00000111_11111111) as usize],
+            PPUCTRL | PPUMASK | OAMADDR | PPUSCROLL | PPUADDR => self.ppu.open_bus,
+            PPUSTATUS => self.ppu.peek_status(),
+            OAMDATA => self.ppu.oam_data[self.ppu.oam_addr as usize],
+            0x2008 ..= PPU_REGISTERS_MIRRORS_END => self.peek(addr & 0b00100000_00000111),
+            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
+    // len bytes from start for hex dumps, wraps past 0xFFFF
+    pub fn read_range(&self, start: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.peek(start.wrapping_add(i as u16))).collect()
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    // For PPU options that have to be set before running, e.g. warm_up
+    pub fn ppu_mut(&mut self) -> &mut PPU {
+        &mut self.ppu
+    }
+
+    // Raise an NMI now rather than waiting for vblank, for testing NMI handlers
+    pub fn trigger_nmi(&mut self) {
+        self.ppu.nmi_interrupt = Some(1);
+    }
+
+    pub fn nmi_pending(&self) -> bool {
+        self.ppu.nmi_interrupt.is_some()
+    }
+
+    // The PPU runs 3 dots per CPU cycle on NTSC and 3.2 on PAL. The bus itself only
+    // emulates NTSC timing, these are for tools working with either region
+    pub fn cpu_to_ppu_cycles(cycles: usize, tv_system: TvSystem) -> usize {
+        match tv_system {
+            TvSystem::NTSC => cycles * 3,
+            TvSystem::PAL => cycles * 16 / 5,
+        }
+    }
+
+    pub fn ppu_to_cpu_cycles(cycles: usize, tv_system: TvSystem) -> usize {
+        match tv_system {
+            TvSystem::NTSC => cycles / 3,
+            TvSystem::PAL => cycles * 5 / 16,
+        }
+    }
+
+    // Load sprites without going through OAMADDR/OAMDATA, handy for setting up render tests
+    pub fn set_oam(&mut self, oam: [u8; 256]) {
+        self.ppu.set_oam(oam);
+    }
+
+    // Snapshot of both controllers for UIs, without going through the $4016/$4017 shift registers
+    pub fn read_controllers(&self) -> (u8, u8) {
+        (self.joypad.buttons().bits(), self.joypad2.buttons().bits())
+    }
+
+    // The gameloop callback only gets controller 1, frontends set controller 2 through here
+    pub fn joypad2_mut(&mut self) -> &mut Joypad {
+        &mut self.joypad2
+    }
+
+    // True while an OAM DMA is still holding the CPU
+    pub fn dma_in_progress(&self) -> bool {
+        self.dma_pending || self.dma_stall > 0
+    }
+
+    // Called once the instruction that wrote OAMDMA has finished, that's where the DMA
+    // begins. 513 cycles, plus one for alignment when it starts on an odd cycle
+    pub fn start_dma_stall(&mut self) {
+        if self.dma_pending {
+            self.dma_pending = false;
+            self.dma_stall = 513 + self.cycles % 2;
+        }
+    }
+
+    // Burn whatever is left of the DMA stall, the CPU can't run until it's over
+    pub fn run_dma_stall(&mut self) {
+        self.start_dma_stall();
+        // Chunks of 85 so the 3x PPU cycle count still fits in a u8
+        while self.dma_stall > 0 {
+            self.tick(self.dma_stall.min(85) as u8);
+        }
+    }
+
     pub fn tick(&mut self, cycles: u8) {
         self.cycles += cycles as usize;
+        self.dma_stall = self.dma_stall.saturating_sub(cycles as usize);
 
         let new_frame = self.ppu.tick(cycles * 3); // PPU clock is 3x faster than CPU clock
 
@@ -64,19 +253,33 @@ const PPUDATA: u16   = 0x2007;
 const OAMDMA: u16    = 0x4014; 
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
+// Fold RAM and PPU register mirrors down to the address they repeat
+fn canonical_addr(addr: u16) -> u16 {
+    match addr {
+        RAM ..= RAM_MIRRORS_END => addr & 0b00000111_11111111,
+        0x2000 ..= PPU_REGISTERS_MIRRORS_END => addr & 0b00100000_00000111,
+        _ => addr,
+    }
+}
+
 impl Memory for Bus<'_> {
     fn mem_read(&mut self, addr: u16) -> u8 {
+        if let Some(memory) = &self.flat_memory {
+            return memory[addr as usize];
+        }
         match addr {
             RAM ..= RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
 
-            PPUCTRL | PPUMASK | OAMADDR | PPUSCROLL | PPUADDR | OAMDMA => {
+            PPUCTRL | PPUMASK | OAMADDR | PPUSCROLL | PPUADDR => {
                 //panic!("Attempting to read from write-only PPU Address {:X}", addr);
-                0
+                self.ppu.open_bus
             }
 
+            OAMDMA => 0,
+
             PPUSTATUS => self.ppu.read_status(),
             PPUDATA => self.ppu.read_data(),
             OAMDATA => self.ppu.read_oam_data(),
@@ -96,46 +299,43 @@ impl Memory for Bus<'_> {
             }
 
             0x4017 => {
-                // ignore joypad 2
-                0
+                self.joypad2.read()
             }
 
 
             0x8000..=0xFFFF => self.read_prg_rom(addr),
 
             _ => {
-                println!("Unknown memory access at {:X}", addr);
+                self.unknown_access(addr, false);
                 0
             }
         }
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if self.write_watch == Some(self.watch_addr(addr)) {
+            self.watch_hit = Some(data);
+        }
+        if let Some(memory) = &mut self.flat_memory {
+            memory[addr as usize] = data;
+            return;
+        }
+
         match addr {
             RAM ..= RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b00000111_11111111;
                 self.cpu_vram[mirror_down_addr as usize] = data;
             }
 
-            PPUCTRL => self.ppu.write_to_control(data),
-            PPUMASK => self.ppu.write_to_mask(data),
-            PPUSTATUS => {},//panic!("Attempting to write {:X} to PPU Status", data),
-            PPUADDR => self.ppu.write_to_ppu_addr(data),
-            PPUSCROLL => self.ppu.write_to_scroll(data),
-
-            OAMADDR => self.ppu.write_to_oam_addr(data),
-            OAMDATA => self.ppu.write_to_oam_data(data),
-            PPUDATA => self.ppu.write_to_data(data),
-
-            0x2008 ..= PPU_REGISTERS_MIRRORS_END => {
-                let mirror_down_addr = addr & 0b00100000_00000111;
-                self.mem_write(mirror_down_addr, data);
-            }
+            // The PPU decodes its own registers and their mirrors
+            PPUCTRL ..= PPU_REGISTERS_MIRRORS_END => self.ppu.write_register(addr, data),
             
             OAMDMA => {
-                let start =( (data as u16) << 8) as usize;
-                let end = ((data as u16) << 8 | 0xFF) as usize;
-                self.ppu.oam_dma(&self.cpu_vram[start..end]);
+                // Whole 256 byte page through the bus, so RAM mirrors and ROM pages work too
+                let page = (data as u16) << 8;
+                let bytes: Vec<u8> = (0..=0xFF).map(|i| self.mem_read(page | i)).collect();
+                self.ppu.oam_dma(&bytes);
+                self.dma_pending = true;
             }
 
             0x4000..=0x4013 | 0x4015 => {
@@ -144,21 +344,244 @@ impl Memory for Bus<'_> {
             }
 
             0x4016 => {
+                // Strobe is shared by both controller ports
                 self.joypad.write(data);
+                self.joypad2.write(data);
             }
 
             0x4017 => {
-                // ignore joypad 2
-                
+                // APU frame counter on write, not emulated
             }
 
+            // No mapper registers on NROM, the write goes nowhere
             0x8000..=0xFFFF => {
-                panic!("Attempting to write to Cartridge ROM space")
+                self.rom_write = Some(addr);
+                self.unknown_access(addr, true);
             }
 
             _ => {
-                println!("Unknown memory access at {:X}", addr);
+                self.unknown_access(addr, true);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::input::joypad::JoypadButton;
+    use crate::render::{self, frame::Frame, palette};
+
+    #[test]
+    fn test_set_gameloop_callback() {
+        let frames = std::cell::Cell::new(0);
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.set_gameloop_callback(|_, _| frames.set(frames.get() + 1));
+
+        while bus.ppu().frames == 0 {
+            bus.tick(85);
+        }
+        assert_eq!(frames.get(), 1);
+    }
+
+    #[test]
+    fn test_new_with_oam_pattern() {
+        let mut bus = Bus::new_with_oam_pattern(test_rom(), 0xFF, |_, _| {});
+        bus.mem_write(0x2003, 0x10); // OAMADDR
+        assert_eq!(bus.mem_read(0x2004), 0xFF);
+    }
+
+    #[test]
+    fn test_ppu_warm_up_through_bus() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.ppu_mut().warm_up = true;
+
+        bus.mem_write(0x2000, 0x80);
+        assert_eq!(bus.ppu().control.bits(), 0);
+
+        while bus.cycles < 29658 {
+            bus.tick(85);
+        }
+        bus.mem_write(0x2000, 0x80);
+        assert_eq!(bus.ppu().control.bits(), 0x80);
+    }
+
+    #[test]
+    fn test_open_bus_through_bus() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        bus.ppu_mut().open_bus_decay_frames = 2;
+
+        bus.mem_write(0x2005, 0x1F);
+        assert_eq!(bus.peek(0x2000), 0x1F);
+        assert_eq!(bus.peek(0x2002), 0x1F);
+        assert_eq!(bus.mem_read(0x2000), 0x1F);
+
+        while bus.ppu().frames < 2 {
+            bus.tick(85);
+        }
+        assert_eq!(bus.peek(0x2000), 0);
+        assert_eq!(bus.mem_read(0x2000), 0);
+    }
+
+    #[test]
+    fn test_dma_in_progress() {
+        let mut bus = Bus::new(test_rom(), |_, _| {});
+        assert!(!bus.dma_in_progress());
+
+        bus.mem_write(0x4014, 0x02);
+        assert!(bus.dma_in_progress());
+
+        bus.run_dma_stall();
+        assert!(!bus.dma_in_progress());
+        assert_eq!(bus.cycles, 513);
+    }
+
+    #[test]
+    fn test_cpu_ppu_cycle_conversion() {
+        assert_eq!(Bus::cpu_to_ppu_cycles(10, TvSystem::NTSC), 30);
+        assert_eq!(Bus::ppu_to_cpu_cycles(30, TvSystem::NTSC), 10);
+    }
+
+    #[test]
+    fn test_cpu_ppu_cycle_conversion_pal() {
+        assert_eq!(Bus::cpu_to_ppu_cycles(10, TvSystem::PAL), 32);
+        assert_eq!(Bus::ppu_to_cpu_cycles(32, TvSystem::PAL), 10);
+    }
+
+    #[test]
+    fn test_prg_rom_16k_mirrored() {
+        let mut prg_rom = vec![0; 0x4000];
+        prg_rom[0x0010] = 0x42;
+        let mut bus = Bus::new(Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            ..test_rom()
+        }, |_, _|{});
+
+        assert_eq!(bus.mem_read(0x8010), 0x42);
+        assert_eq!(bus.mem_read(0xC010), 0x42);
+    }
+
+    #[test]
+    fn test_prg_rom_larger_than_32k() {
+        let prg_rom: Vec<u8> = (0..0x20000).map(|i| (i / 0x4000) as u8).collect(); // 8 x 16K banks
+        let mut bus = Bus::new(Rom {
+            prg_rom,
+            chr_rom: vec![0; 0x2000],
+            ..test_rom()
+        }, |_, _|{});
+
+        assert_eq!(bus.mem_read(0x8000), 0);
+        assert_eq!(bus.mem_read(0xC000), 1);
+        assert_eq!(bus.mem_read(0xFFFF), 1);
+    }
+
+    #[test]
+    fn test_unknown_access_warnings() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        assert_eq!(bus.mem_read(0x5000), 0);
+        bus.mem_write(0x6000, 0x42);
+
+        assert_eq!(bus.take_access_warnings(), vec![(0x5000, false), (0x6000, true)]);
+        assert!(bus.take_access_warnings().is_empty());
+    }
+
+    #[test]
+    fn test_write_watch_through_mirrors() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        bus.set_write_watch(Some(0x0010));
+        bus.mem_write(0x0810, 0x42);
+        assert_eq!(bus.take_watch_hit(), Some(0x42));
+        bus.mem_write(0x1810, 0x43);
+        assert_eq!(bus.take_watch_hit(), Some(0x43));
+
+        bus.set_write_watch(Some(0x2008)); // Watch given as a mirror itself
+        bus.mem_write(0x2000, 0x80);
+        assert_eq!(bus.take_watch_hit(), Some(0x80));
+    }
+
+    #[test]
+    fn test_rom_write_dropped() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        bus.mem_write(0x8000, 0x42);
+
+        assert_eq!(bus.mem_read(0x8000), 1);
+        assert_eq!(bus.take_access_warnings(), vec![(0x8000, true)]);
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        bus.mem_write(0x0010, 0x42);
+        assert_eq!(bus.peek(0x0810), 0x42); // RAM mirror
+        assert_eq!(bus.peek(0x8000), 1);
+
+        bus.ppu.status.set_vertical_blank(true);
+        assert_eq!(bus.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.peek(0x200A) & 0x80, 0x80);
+        assert!(bus.ppu.status.in_vertical_blank());
+    }
+
+    #[test]
+    fn test_peek_matches_mem_read_for_write_only_registers() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        bus.mem_write(0x2005, 0x3C);
+        bus.ppu.status.set_vertical_blank(true);
+
+        for addr in [0x2000, 0x2001, 0x2003, 0x2005, 0x2006, 0x2008, 0x3FFE, 0x2002] {
+            let peeked = bus.peek(addr);
+            assert_eq!(peeked, bus.mem_read(addr), "{:04x}", addr);
+        }
+    }
+
+    #[test]
+    fn test_read_range() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        for i in 0..16 {
+            bus.mem_write(0x0200 + i, (i * 3) as u8);
+        }
+
+        let expected: Vec<u8> = (0..16).map(|i| i * 3).collect();
+        assert_eq!(bus.read_range(0x0200, 16), expected);
+        assert_eq!(bus.read_range(0xFFFF, 2), vec![1, 0]); // PRG then RAM
+    }
+
+    #[test]
+    fn test_read_controllers() {
+        let mut bus = Bus::new(test_rom(), |_, _|{});
+        bus.joypad.set_button_pressed_status(JoypadButton::START, true);
+        bus.joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.joypad2_mut().set_button_pressed_status(JoypadButton::BUTTON_B, true);
+
+        assert_eq!(bus.read_controllers(), (0b0000_1001, 0b0000_0010));
+
+        // Snapshot doesn't advance the serial read
+        assert_eq!(bus.mem_read(0x4016), 1);
+        assert_eq!(bus.mem_read(0x4017), 0); // Controller 2, A
+        assert_eq!(bus.mem_read(0x4017), 1); // B
+    }
+
+    #[test]
+    fn test_set_oam_renders_sprite() {
+        let mut bus = Bus::new(test_rom(), |_, _|{}); // Every CHR byte is 0x02
+        let mut oam = [0xF0; 256]; // Everything off the bottom of the screen
+        oam[0] = 50; // y
+        oam[1] = 0; // tile
+        oam[2] = 0; // attributes
+        oam[3] = 100; // x
+        bus.set_oam(oam);
+        assert_eq!(bus.ppu.oam_data, oam);
+
+        bus.ppu.palette[3] = 0x0F;
+        bus.ppu.palette[0x13] = 0x30;
+
+        let mut frame = Frame::new();
+        render::render(&bus.ppu, &mut frame);
+
+        // 0x02 in both planes gives colour 3 in the 7th column of each row
+        let base = 50 * 3 * 256 + 106 * 3;
+        let rgb = (frame.data[base], frame.data[base + 1], frame.data[base + 2]);
+        assert_eq!(rgb, palette::SYSTEM_PALLETE[0x30]);
+    }
+}
@@ -1,59 +1,162 @@
+use std::collections::HashMap;
+use std::fmt;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOUR_SCREEN,
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TvSystem {
+    NTSC,
+    PAL,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+    NotINes,
+    Nes2Unsupported,
+    Truncated { expected: usize, actual: usize }, // File shorter than the header's PRG/CHR sizes
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotINes => write!(f, "NES tag not found in the file header. File must be in iNES format"),
+            RomError::Nes2Unsupported => write!(f, "NES2.0 format not supported (yet)"),
+            RomError::Truncated { expected, actual } =>
+                write!(f, "ROM file is truncated, header needs {} bytes but the file has {}", expected, actual),
+        }
+    }
+}
+
+// The 16 byte iNES header parsed into named fields
+#[derive(Debug, PartialEq)]
+pub struct INesHeader {
+    pub prg_size: usize, // In bytes
+    pub chr_size: usize, // In bytes
+    pub flags6: u8,
+    pub flags7: u8,
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    pub tv_system: TvSystem,
+}
+
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
-    pub screen_mirroring: Mirroring,
+    pub header: INesHeader,
 }
 
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; 
 const PRG_ROM_PAGE_SIZE: usize = 16 * 1024; // 16kB
 const CHR_ROM_PAGE_SIZE: usize = 8 * 1024; // 8kB
 
-impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
-            return Err("NES tag not found in the file header. File must be in iNES format".to_string());
+impl INesHeader {
+    pub fn parse(raw: &[u8]) -> Result<INesHeader, RomError> {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
+            return Err(RomError::NotINes);
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
+        let flags6 = raw[6];
+        let flags7 = raw[7];
+        let mapper = (flags7 & 0b1111_0000) | (flags6 >> 4);
         
-        let ver = (raw[7] >> 2) & 0b11;
+        let ver = (flags7 >> 2) & 0b11;
         if ver != 0 {
-            return Err("NES2.0 format not supported (yet)".to_string());
+            return Err(RomError::Nes2Unsupported);
         }
         
-        let four_screen = raw[6] & 0b1000 != 0;
-        let vertical_mirroring = raw[6] & 0b1 != 0;
-        let screen_mirroring = match(four_screen, vertical_mirroring) {
+        let four_screen = flags6 & 0b1000 != 0;
+        let vertical_mirroring = flags6 & 0b1 != 0;
+        let mirroring = match(four_screen, vertical_mirroring) {
             (true, _) => Mirroring::FOUR_SCREEN,
             (false, true) => Mirroring::VERTICAL,
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
-        
-        let skip_trainer = raw[6] & 0b100 != 0;
+        let tv_system = if raw[9] & 0b1 != 0 { TvSystem::PAL } else { TvSystem::NTSC };
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        Ok(INesHeader {
+            prg_size: raw[4] as usize * PRG_ROM_PAGE_SIZE,
+            chr_size: raw[5] as usize * CHR_ROM_PAGE_SIZE,
+            flags6,
+            flags7,
+            mapper,
+            mirroring,
+            battery: flags6 & 0b10 != 0,
+            trainer: flags6 & 0b100 != 0,
+            tv_system,
+        })
+    }
+}
+
+impl Rom {
+    pub fn new(raw: &Vec<u8>) -> Result<Rom, RomError> {
+        let header = INesHeader::parse(raw)?;
+
+        let prg_rom_start = 16 + if header.trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start + header.prg_size;
+
+        let expected = chr_rom_start + header.chr_size;
+        if raw.len() < expected {
+            return Err(RomError::Truncated { expected, actual: raw.len() });
+        }
 
         Ok(Rom {
-            prg_rom: raw[prg_rom_start .. (prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start .. (chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
+            prg_rom: raw[prg_rom_start .. (prg_rom_start + header.prg_size)].to_vec(),
+            chr_rom: raw[chr_rom_start .. (chr_rom_start + header.chr_size)].to_vec(),
+            header,
         })
    
     }
+
+    pub fn mapper(&self) -> u8 {
+        self.header.mapper
+    }
+
+    pub fn screen_mirroring(&self) -> Mirroring {
+        self.header.mirroring
+    }
+
+    // A CHR size of 0 in the header means the cartridge has CHR RAM instead
+    pub fn uses_chr_ram(&self) -> bool {
+        self.header.chr_size == 0
+    }
+
+    // CRC32 of PRG ROM, what override databases are keyed by
+    pub fn prg_crc32(&self) -> u32 {
+        crc32(&self.prg_rom)
+    }
+
+    // Some dumps have the wrong mirroring in their header, correct it when the PRG CRC is
+    // in the table. Returns whether an override was applied
+    pub fn apply_mirroring_override(&mut self, crc_table: &HashMap<u32, Mirroring>) -> bool {
+        match crc_table.get(&self.prg_crc32()) {
+            Some(mirroring) => {
+                self.header.mirroring = *mirroring;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Standard CRC32 (IEEE, reflected, polynomial 0xEDB88320)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
 }
 
 pub mod test {
@@ -108,8 +211,66 @@ pub mod test {
         let rom: Rom = Rom::new(&test_rom).unwrap();
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
-        assert_eq!(rom.mapper, 3);
-        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.mapper(), 3);
+        assert_eq!(rom.screen_mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        //                <----NES-HEADER-=---->, PRG,  CHR,  F6,   F7,   lPRG, F9,   <--------RESERVED-------------->
+        let header = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x57, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let header = INesHeader::parse(&header).unwrap();
+
+        assert_eq!(header, INesHeader {
+            prg_size: 2 * PRG_ROM_PAGE_SIZE,
+            chr_size: CHR_ROM_PAGE_SIZE,
+            flags6: 0x57,
+            flags7: 0x10,
+            mapper: 0x15,
+            mirroring: Mirroring::VERTICAL,
+            battery: true,
+            trainer: true,
+            tv_system: TvSystem::PAL,
+        });
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(&[]), 0);
+    }
+
+    #[test]
+    fn test_mirroring_override() {
+        let test_rom = create_rom(TestRom {
+            header: vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            trainer: None,
+            pgr_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE]
+        });
+        let mut rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.screen_mirroring(), Mirroring::HORIZONTAL);
+
+        let mut crc_table = HashMap::new();
+        crc_table.insert(0x1234_5678, Mirroring::FOUR_SCREEN);
+        assert!(!rom.apply_mirroring_override(&crc_table));
+        assert_eq!(rom.screen_mirroring(), Mirroring::HORIZONTAL);
+
+        crc_table.insert(crc32(&[1; PRG_ROM_PAGE_SIZE]), Mirroring::VERTICAL);
+        assert!(rom.apply_mirroring_override(&crc_table));
+        assert_eq!(rom.screen_mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_uses_chr_ram() {
+        let chr_ram_rom = create_rom(TestRom {
+            header: vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            trainer: None,
+            pgr_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![]
+        });
+        assert!(Rom::new(&chr_ram_rom).unwrap().uses_chr_ram());
+        assert!(!test_rom().uses_chr_ram());
     }
 
     #[test]
@@ -125,8 +286,8 @@ pub mod test {
 
         assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
-        assert_eq!(rom.mapper, 3);
-        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.mapper(), 3);
+        assert_eq!(rom.screen_mirroring(), Mirroring::VERTICAL);
     }
 
     #[test]
@@ -141,9 +302,25 @@ pub mod test {
         let rom = Rom::new(&test_rom);
         match rom {
             Result::Ok(_) => assert!(false, "Attempted to load NES2.0 rom"),
-            Result::Err(str) => assert_eq!(str, "NES2.0 format not supported (yet)")
+            Result::Err(str) => assert_eq!(str.to_string(), "NES2.0 format not supported (yet)")
         }
 
 
     }
+
+    #[test]
+    fn test_truncated() {
+        let test_rom = create_rom(TestRom {
+            header: vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            trainer: None,
+            pgr_rom: vec![1; PRG_ROM_PAGE_SIZE], // Header claims 2 pages
+            chr_rom: vec![]
+        });
+
+        assert_eq!(Rom::new(&test_rom).err(), Some(RomError::Truncated {
+            expected: 16 + 2 * PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE,
+            actual: 16 + PRG_ROM_PAGE_SIZE,
+        }));
+        assert_eq!(Rom::new(&vec![0x4E, 0x45]).err(), Some(RomError::NotINes));
+    }
 }
\ No newline at end of file
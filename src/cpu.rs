@@ -1,7 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
 
 use crate::bus::Bus;
+use crate::cartridge::Rom;
 use crate::opcodes;
+use crate::trace;
 use bitflags::bitflags;
 
 bitflags! {
@@ -42,6 +45,46 @@ pub struct CPU<'a> {
     pub status: CPUFlags,
     pub program_counter: u16,
     pub bus: Bus<'a>,
+
+    // Accuracy: RMW instructions write the unmodified value back before the result
+    pub rmw_double_write: bool,
+
+    // Accuracy: indexed reads that cross a page read the un-fixed-up address first
+    pub dummy_read: bool,
+
+    // Refuse to execute unofficial opcodes
+    pub strict_mode: bool,
+    // Per opcode overrides of strict_mode
+    illegal_opcodes: HashMap<u8, bool>,
+
+    // Names for JMP/JSR targets in traces
+    symbols: HashMap<u16, String>,
+
+    // Opcode of the most recently executed instruction
+    last_opcode: Option<u8>,
+
+    // Set when an operand is fetched for NoneAddressing, reported as UnsupportedMode
+    unsupported_mode: bool,
+}
+
+const READS_WITH_PAGE_PENALTY: [&str; 11] = ["ADC", "AND", "CMP", "EOR", "LDA", "LDX", "LDY", "ORA", "SBC", "*LAX", "*NOP"];
+
+// Most distinct instruction addresses detect_tight_loop treats as still stuck
+const TIGHT_LOOP_ADDRESSES: usize = 8;
+
+#[derive(Debug, PartialEq)]
+pub enum CpuError {
+    UnknownOpcode(u8), // Not in the opcode table or not implemented
+    IllegalOpcode(u8), // Unofficial opcode while strict_mode is on
+    UnsupportedMode(u8), // Opcode table asked for an operand the addressing mode doesn't have
+    RomWrite(u16), // Write to cartridge ROM while strict_mode is on
+}
+
+// Interrupts raised but not yet serviced by the CPU
+#[derive(Debug, PartialEq)]
+pub struct InterruptState {
+    pub nmi: bool,
+    pub irq: bool,
 }
 
 #[derive(Debug)]
@@ -115,11 +158,23 @@ impl<'a> CPU<'a> {
             status:  CPUFlags::from_bits_truncate(0b0010_0100),
             
             program_counter: 0,
-            bus
-            
+            bus,
+
+            rmw_double_write: false,
+            dummy_read: false,
+            strict_mode: false,
+            illegal_opcodes: HashMap::new(),
+            symbols: HashMap::new(),
+            last_opcode: None,
+            unsupported_mode: false,
         }
     }   
 
+    // CPU on a fresh bus with no gameloop callback, saves the boilerplate in tests and tools
+    pub fn from_rom(rom: Rom) -> CPU<'a> {
+        CPU::new(Bus::new(rom, |_, _|{}))
+    }
+
     pub fn reset(&mut self) {
         self.register_a = 0;
         self.register_x = 0;
@@ -145,6 +200,22 @@ impl<'a> CPU<'a> {
         //self.mem_write_u16(0xFFFC, 0x0600);
     }
 
+    // CPU on a flat 64K memory image with no cartridge or NES memory map, for testing
+    // bare 6502 code. Execution starts at the image's reset vector
+    pub fn new_flat(image: &[u8; 0x10000]) -> CPU<'a> {
+        let mut cpu = CPU::new(Bus::new_flat(image));
+        cpu.reset();
+        cpu
+    }
+
+    // Copy a 2KB image over the NES bus's internal RAM (0x0000-0x07FF). The cartridge still
+    // backs everything above it, use new_flat for a full 64K image
+    pub fn load_ram(&mut self, ram: &[u8; 0x800]) {
+        for (addr, byte) in ram.iter().enumerate() {
+            self.mem_write(addr as u16, *byte);
+        }
+    }
+
     pub fn get_operand_address_from_base(&mut self, mode: &AddressingMode, base: u16) -> u16 {
         match mode {
             // Immediate addressing deals with the number itself
@@ -221,8 +292,10 @@ impl<'a> CPU<'a> {
                deref
            }
 
+           // No operand to fetch, execute_instruction turns this into an error
            AddressingMode::NoneAddressing => {
-               panic!("Mode {:?} is not supported", mode)
+               self.unsupported_mode = true;
+               0
            }
 
        }
@@ -231,11 +304,24 @@ impl<'a> CPU<'a> {
 
     // Where am I addressing data from?
     fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
-        let addr = self.get_operand_address_from_base(mode, self.program_counter);
-        if ((addr >> 8) as u8) != ((self.program_counter >> 8) as u8) {
-            self.bus.tick(1); // +1 if crosses page boundary
-        }
-        addr
+        self.get_operand_address_from_base(mode, self.program_counter)
+    }
+
+    // Base and effective address of an indexed operand, None for other modes.
+    // program_counter must point at the operand
+    fn indexed_addresses(&mut self, mode: &AddressingMode) -> Option<(u16, u16)> {
+        let (base, index) = match mode {
+            AddressingMode::Absolute_X => (self.mem_read_u16(self.program_counter), self.register_x),
+            AddressingMode::Absolute_Y => (self.mem_read_u16(self.program_counter), self.register_y),
+            AddressingMode::Indirect_Y => {
+                let ptr = self.mem_read(self.program_counter);
+                let lo = self.mem_read(ptr as u16);
+                let hi = self.mem_read(ptr.wrapping_add(1) as u16);
+                ((hi as u16) << 8 | (lo as u16), self.register_y)
+            }
+            _ => return None,
+        };
+        Some((base, base.wrapping_add(index as u16)))
     }
 
     fn push_to_stack(&mut self, data: u8) {
@@ -285,10 +371,19 @@ impl<'a> CPU<'a> {
         self.register_a = data;
     }
 
+    // Hardware writes the value it just read straight back before writing the modified one,
+    // which memory-mapped registers can observe
+    fn rmw_dummy_write(&mut self, addr: u16, data: u8) {
+        if self.rmw_double_write {
+            self.mem_write(addr, data);
+        }
+    }
+
     // Arithmetic Shift Left 
     fn asl(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
+        self.rmw_dummy_write(addr, data);
 
         data = self.shift_left(data);
         self.mem_write(addr, data);
@@ -321,6 +416,7 @@ impl<'a> CPU<'a> {
     fn dec(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
+        self.rmw_dummy_write(addr, data);
         self.update_zero_and_negative_flags(data.wrapping_sub(1));
         self.mem_write(addr, data.wrapping_sub(1));
     }
@@ -418,6 +514,7 @@ impl<'a> CPU<'a> {
     fn lsr(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
+        self.rmw_dummy_write(addr, data);
 
         data = self.shift_right(data);
         self.mem_write(addr, data);
@@ -457,6 +554,7 @@ impl<'a> CPU<'a> {
     fn inc(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let data = self.mem_read(addr);
+        self.rmw_dummy_write(addr, data);
         self.mem_write(addr, data.wrapping_add(1));
         self.update_zero_and_negative_flags(data.wrapping_add(1));
     }
@@ -517,6 +615,7 @@ impl<'a> CPU<'a> {
     fn rol(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
+        self.rmw_dummy_write(addr, data);
         data = self.rotate_left(data);
         self.mem_write(addr, data);
     }
@@ -533,6 +632,7 @@ impl<'a> CPU<'a> {
     fn ror(&mut self, mode: &AddressingMode) {
         let addr = self.get_operand_address(mode);
         let mut data = self.mem_read(addr);
+        self.rmw_dummy_write(addr, data);
         data = self.rotate_right(data);
         self.mem_write(addr, data);
     }
@@ -629,6 +729,38 @@ impl<'a> CPU<'a> {
         self.status.set(CPUFlags::ZERO, compare == 0);
     }
 
+    pub fn set_symbols(&mut self, symbols: HashMap<u16, String>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbol(&self, addr: u16) -> Option<&str> {
+        self.symbols.get(&addr).map(|name| name.as_str())
+    }
+
+    // Opcode and mnemonic of the instruction that ran last, for debug panels
+    pub fn last_instruction(&self) -> Option<(u8, &'static str)> {
+        self.last_opcode
+            .and_then(opcodes::lookup)
+            .map(|opcode| (opcode.code, opcode.mnemonic))
+    }
+
+    // Disassembly of the next instruction, leaves the CPU and bus untouched
+    pub fn peek_disasm(&self) -> String {
+        trace::disasm(self)
+    }
+
+    // (NMI, RESET, IRQ/BRK) vectors as currently mapped
+    pub fn vectors(&mut self) -> (u16, u16, u16) {
+        (self.mem_read_u16(0xFFFA), self.mem_read_u16(0xFFFC), self.mem_read_u16(0xFFFE))
+    }
+
+    pub fn pending_interrupts(&self) -> InterruptState {
+        InterruptState {
+            nmi: self.bus.nmi_pending(),
+            irq: false, // Nothing on the bus raises IRQs yet
+        }
+    }
+
     pub fn run(&mut self) {
         self.run_with_callback(|_| {});
     }
@@ -646,278 +778,458 @@ impl<'a> CPU<'a> {
         self.program_counter = self.mem_read_u16(0xFFFA);
     }
 
+    // Only hand instructions whose address falls inside the range to the callback
+    pub fn run_with_trace_filter<F>(&mut self, range: Range<u16>, mut callback: F)
+    where F: FnMut(&mut CPU)
+    {
+        self.run_with_callback(|cpu| {
+            if range.contains(&cpu.program_counter) {
+                callback(cpu);
+            }
+        });
+    }
+
+    // Count how many times each mnemonic executes, BRK included
+    pub fn run_with_opcode_histogram(&mut self) -> HashMap<&'static str, u64> {
+        let mut histogram = HashMap::new();
+        self.run_with_callback(|cpu| {
+            let code = cpu.mem_read(cpu.program_counter);
+            if let Some(opcode) = opcodes::lookup(code) {
+                *histogram.entry(opcode.mnemonic).or_insert(0) += 1;
+            }
+        });
+        histogram
+    }
+
+    // Run up to window instructions, true when they only ever revisit a handful of addresses
+    // (a JMP to itself, a polling loop). Stops early on BRK, errors or leaving the loop
+    pub fn detect_tight_loop(&mut self, window: usize) -> bool {
+        let mut visited = HashSet::new();
+        let mut repeated = false;
+        for _ in 0..window {
+            if !visited.insert(self.program_counter) {
+                repeated = true;
+            }
+            if visited.len() > TIGHT_LOOP_ADDRESSES {
+                return false;
+            }
+            match self.try_step() {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {},
+            }
+        }
+        repeated
+    }
+
+    // Run until an instruction writes to addr and return the value written.
+    // None if the program stops (BRK or an error) first
+    pub fn run_until_write(&mut self, addr: u16) -> Option<u8> {
+        self.bus.set_write_watch(Some(addr));
+        let hit = loop {
+            match self.try_step() {
+                Ok(0) | Err(_) => break None,
+                Ok(_) => {},
+            }
+
+            if let Some(data) = self.bus.take_watch_hit() {
+                break Some(data);
+            }
+        };
+        self.bus.set_write_watch(None);
+        hit
+    }
+
+    // Run one frame's worth of emulation and return, so frontends that can't block
+    // (wasm, async) can drive one frame per callback. False when the CPU halted first
+    pub fn run_until_frame(&mut self) -> bool {
+        let frame = self.bus.ppu().frames;
+        while self.bus.ppu().frames == frame {
+            match self.try_step() {
+                Ok(0) | Err(_) => return false,
+                Ok(_) => {},
+            }
+        }
+        true
+    }
+
+    // Step until the PPU enters vblank, returns the number of instructions run.
+    // Also stops on BRK or an error so a halted program can't hang the debugger
+    pub fn run_until_vblank(&mut self) -> usize {
+        let mut count = 0;
+        let mut was_in_vblank = self.bus.ppu().status.in_vertical_blank();
+        loop {
+            match self.try_step() {
+                Ok(0) | Err(_) => return count,
+                Ok(_) => count += 1,
+            }
+
+            let in_vblank = self.bus.ppu().status.in_vertical_blank();
+            if in_vblank && !was_in_vblank {
+                return count;
+            }
+            was_in_vblank = in_vblank;
+        }
+    }
+
     pub fn run_with_callback<F>(&mut self, mut callback: F) 
     where F: FnMut(&mut CPU)
      {
-        let ref opcodes: HashMap<u8, &'static opcodes::OpCode> = *opcodes::OPCODES_MAP;
         loop {
+            self.bus.run_dma_stall();
             if let Some(_nmi) = self.bus.poll_nmi_status() {
                 self.interrupt_nmi();
             }
 
 
             callback(self);
-            
-            let opcode = opcodes.get(&self.mem_read(self.program_counter)).unwrap();
-            self.program_counter += 1;
-
-            match opcode.code {
-                // BRK
-                0x00 => return,
-
-                // NOP
-                0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {}, // Do nothing
-                0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xD4 | 0xE2 | 0xF4 => {}, // Do nothing
-                0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {}, // Do nothing
-
-                // ADC
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
-                
-                // ANC
-                0x0B | 0x2B => {
-                    self.and(&opcode.mode);
-                    self.status.set(CPUFlags::CARRY, self.register_a >> 7 == 1);
-                } 
-
-                // ALR
-                0x4B => {
-                    self.and(&opcode.mode);
-                    self.lsr_acc();
-                }
 
-                // AND
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
+            match self.execute_instruction() {
+                Ok(true) => {},
+                Ok(false) => return,
+                Err(err) => panic!("CPU error at {:04x}: {:?}", self.program_counter, err),
+            }
+        }
+    }
 
-                // ASL ACCUMULATOR
-                0x0A => self.asl_acc(),
+    // Run a single instruction, servicing a pending NMI first. Returns the CPU cycles taken
+    // (usize, an OAM DMA stall alone is over 255), BRK stops the CPU and returns 0.
+    // An OAM DMA started by the previous step stays in progress (Bus::dma_in_progress)
+    // until this one pays for it, its cycles are counted here
+    pub fn try_step(&mut self) -> Result<usize, CpuError> {
+        let start = self.bus.cycles;
+        self.bus.run_dma_stall();
+        if let Some(_nmi) = self.bus.poll_nmi_status() {
+            self.interrupt_nmi();
+        }
 
-                // ASL
-                0x06 | 0x16 | 0x0E | 0x1E => self.asl(&opcode.mode),
+        if !self.execute_instruction()? {
+            return Ok(0);
+        }
+        Ok(self.bus.cycles - start)
+    }
 
-                // AXS
-                0x87 | 0x97 | 0x83 | 0x8F => self.axs(&opcode.mode),
+    // Allow or trap a single unofficial opcode regardless of strict_mode
+    pub fn set_illegal_opcode_enabled(&mut self, code: u8, enabled: bool) {
+        self.illegal_opcodes.insert(code, enabled);
+    }
 
-                // BCC
-                0x90 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::CARRY))),         
+    fn illegal_opcode_enabled(&self, code: u8) -> bool {
+        *self.illegal_opcodes.get(&code).unwrap_or(&!self.strict_mode)
+    }
 
-                // BCS
-                0xB0 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::CARRY))),
+    // Run up to n instructions, stopping early on BRK or an error. Returns how many ran
+    pub fn step_n(&mut self, n: usize) -> usize {
+        for count in 0..n {
+            match self.try_step() {
+                Ok(0) | Err(_) => return count,
+                Ok(_) => {},
+            }
+        }
+        n
+    }
+
+    // Fetch and execute the instruction at program_counter, false when it was BRK.
+    // On error program_counter is left pointing at the offending opcode
+    fn execute_instruction(&mut self) -> Result<bool, CpuError> {
+        let code = self.mem_read(self.program_counter);
+        let opcode = match opcodes::lookup(code) {
+            Some(opcode) => opcode,
+            None => return Err(CpuError::UnknownOpcode(code)),
+        };
+        let start = self.program_counter;
+        if opcode.mnemonic.starts_with('*') && !self.illegal_opcode_enabled(code) {
+            return Err(CpuError::IllegalOpcode(code));
+        }
+        self.program_counter += 1;
+        self.last_opcode = Some(code);
+        self.unsupported_mode = false;
+        self.bus.take_rom_write();
+
+        // Only reads pay for crossing a page, stores and read-modify-write always take the
+        // longer path and the opcode table already counts it
+        if READS_WITH_PAGE_PENALTY.contains(&opcode.mnemonic) {
+            if let Some((base, addr)) = self.indexed_addresses(&opcode.mode) {
+                if base & 0xFF00 != addr & 0xFF00 {
+                    if self.dummy_read {
+                        // The 6502 reads before carrying into the high byte
+                        self.mem_read(base & 0xFF00 | addr & 0x00FF);
+                    }
+                    self.bus.tick(1); // +1 if crosses page boundary
+                }
+            }
+        }
 
-                // BEQ
-                0xF0 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::ZERO))),
+        match opcode.code {
+            // BRK
+            0x00 => return Ok(false),
 
-                // BMI
-                0x30 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::NEGATIVE))),
+            // NOP
+            0xEA | 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => {}, // Do nothing
+            0x04 | 0x14 | 0x34 | 0x44 | 0x54 | 0x64 | 0x74 | 0x80 | 0x82 | 0x89 | 0xC2 | 0xD4 | 0xE2 | 0xF4 => {}, // Do nothing
+            0x0C | 0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => {}, // Do nothing
 
-                // BNE
-                0xD0 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::ZERO))),
+            // ADC
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.adc(&opcode.mode),
+            
+            // ANC
+            0x0B | 0x2B => {
+                self.and(&opcode.mode);
+                self.status.set(CPUFlags::CARRY, self.register_a >> 7 == 1);
+            } 
+
+            // ALR
+            0x4B => {
+                self.and(&opcode.mode);
+                self.lsr_acc();
+            }
 
-                // BPL
-                0x10 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::NEGATIVE))),
+            // AND
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&opcode.mode),
 
-                // BVC
-                0x50 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::OVERFLOW))),
+            // ASL ACCUMULATOR
+            0x0A => self.asl_acc(),
 
-                // BVS
-                0x70 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::OVERFLOW))),
+            // ASL
+            0x06 | 0x16 | 0x0E | 0x1E => self.asl(&opcode.mode),
 
-                // BIT
-                0x24 | 0x2C => self.bit(&opcode.mode),
+            // AXS
+            0x87 | 0x97 | 0x83 | 0x8F => self.axs(&opcode.mode),
 
-                // CLC
-                0x18 => self.status.remove(CPUFlags::CARRY),
+            // BCC
+            0x90 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::CARRY))),         
 
-                // CLD
-                0xD8 => self.status.remove(CPUFlags::DECIMAL),
+            // BCS
+            0xB0 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::CARRY))),
 
-                // CLI
-                0x58 => self.status.remove(CPUFlags::INTERRUPT),
+            // BEQ
+            0xF0 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::ZERO))),
 
-                // CLV
-                0xB8 => self.status.remove(CPUFlags::OVERFLOW),
+            // BMI
+            0x30 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::NEGATIVE))),
 
-                // CMP
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.compare(self.register_a, &opcode.mode),
+            // BNE
+            0xD0 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::ZERO))),
 
-                // CPX
-                0xE0 | 0xE4 | 0xEC => self.compare(self.register_x, &opcode.mode),
+            // BPL
+            0x10 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::NEGATIVE))),
 
-                // CPY
-                0xC0 | 0xC4 | 0xCC => self.compare(self.register_y, &opcode.mode),
+            // BVC
+            0x50 => self.program_counter = self.program_counter.wrapping_add(self.branch(!self.status.contains(CPUFlags::OVERFLOW))),
 
-                // DCP
-                0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => {
-                    self.dec(&opcode.mode);
-                    self.compare(self.register_a, &opcode.mode);
-                }
+            // BVS
+            0x70 => self.program_counter = self.program_counter.wrapping_add(self.branch(self.status.contains(CPUFlags::OVERFLOW))),
 
-                // DEC
-                0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(&opcode.mode),
+            // BIT
+            0x24 | 0x2C => self.bit(&opcode.mode),
 
-                // DEX
-                0xCA => self.dex(),
+            // CLC
+            0x18 => self.status.remove(CPUFlags::CARRY),
 
-                // DEY       
-                0x88 => self.dey(),
+            // CLD
+            0xD8 => self.status.remove(CPUFlags::DECIMAL),
 
-                // EOR
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51  => self.eor(&opcode.mode),
+            // CLI
+            0x58 => self.status.remove(CPUFlags::INTERRUPT),
 
-                // INC
-                0xE6 | 0xF6 | 0xEE | 0xFE => self.inc(&opcode.mode),
+            // CLV
+            0xB8 => self.status.remove(CPUFlags::OVERFLOW),
 
-                // INX
-                0xE8 => self.inx(),
+            // CMP
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.compare(self.register_a, &opcode.mode),
 
-                // INY
-                0xC8 => self.iny(),
+            // CPX
+            0xE0 | 0xE4 | 0xEC => self.compare(self.register_x, &opcode.mode),
 
-                // ISB
-                0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => {
-                    self.inc(&opcode.mode);
-                    self.sbc(&opcode.mode);
-                }
+            // CPY
+            0xC0 | 0xC4 | 0xCC => self.compare(self.register_y, &opcode.mode),
 
-                // JMP ABSOLUTE
-                0x4C => self.jmp(true),
-                
-                // JMP INDIRECT
-                0x6C => self.jmp(false),
+            // DCP
+            0xC7 | 0xD7 | 0xCF | 0xDF | 0xDB | 0xC3 | 0xD3 => {
+                self.dec(&opcode.mode);
+                self.compare(self.register_a, &opcode.mode);
+            }
 
-                // JSR
-                0x20 => self.jsr(),
+            // DEC
+            0xC6 | 0xD6 | 0xCE | 0xDE => self.dec(&opcode.mode),
 
-                // LAX
-                0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => {
-                    self.lda(&opcode.mode);
-                    self.tax();
-                },
+            // DEX
+            0xCA => self.dex(),
 
-                // LDA
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(&opcode.mode),
+            // DEY       
+            0x88 => self.dey(),
 
-                // LDX
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
+            // EOR
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51  => self.eor(&opcode.mode),
 
-                // LDY
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
+            // INC
+            0xE6 | 0xF6 | 0xEE | 0xFE => self.inc(&opcode.mode),
 
-                // LSR ACCUMULATOR
-                0x4A => self.lsr_acc(),
-                
-                0x46 | 0x56 | 0x4E | 0x5E => self.lsr(&opcode.mode),
+            // INX
+            0xE8 => self.inx(),
 
-                // ORA
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
+            // INY
+            0xC8 => self.iny(),
 
-                // PHA
-                0x48 => self.push_to_stack(self.register_a),
+            // ISB
+            0xE7 | 0xF7 | 0xEF | 0xFF | 0xFB | 0xE3 | 0xF3 => {
+                self.inc(&opcode.mode);
+                self.sbc(&opcode.mode);
+            }
 
-                // PHP
-                0x08 => self.php(),
+            // JMP ABSOLUTE
+            0x4C => self.jmp(true),
+            
+            // JMP INDIRECT
+            0x6C => self.jmp(false),
 
-                // PLA
-                0x68 => self.pla(),
+            // JSR
+            0x20 => self.jsr(),
 
-                // PLP
-                0x28 => self.plp(),
+            // LAX
+            0xA7 | 0xB7 | 0xAF | 0xBF | 0xA3 | 0xB3 => {
+                self.lda(&opcode.mode);
+                self.tax();
+            },
 
-                // RLA
-                0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => {
-                    self.rol(&opcode.mode);
-                    self.and(&opcode.mode);
-                }
+            // LDA
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.lda(&opcode.mode),
 
-                // RRA
-                0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => {
-                    self.ror(&opcode.mode);
-                    self.adc(&opcode.mode);
-                }
+            // LDX
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.ldx(&opcode.mode),
 
-                // ROL ACCUMULATOR
-                0x2A => {
-                    self.register_a = self.rotate_left(self.register_a);
-                    self.status.set(CPUFlags::ZERO, self.register_a == 0);
-                },
-
-                // ROL
-                0x26 | 0x36 | 0x2E | 0x3E => self.rol(&opcode.mode),
-
-                // ROR ACCUMULATOR
-                0x6A => {
-                    self.register_a = self.rotate_right(self.register_a);
-                    self.status.set(CPUFlags::ZERO, self.register_a == 0);
-                },
-                 
-                // ROR
-                0x66 | 0x76 | 0x6E | 0x7E => self.ror(&opcode.mode),
-
-                // RTI
-                0x40 => self.rti(),
-
-                // RTS
-                0x60 => self.rts(),
-
-                // SBC
-                0xEB | 0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode),
-
-                // SLO
-                0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => {
-                    self.asl(&opcode.mode);
-                    self.ora(&opcode.mode);
-                }
+            // LDY
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.ldy(&opcode.mode),
 
-                // SEC
-                0x38 => self.status.insert(CPUFlags::CARRY),
+            // LSR ACCUMULATOR
+            0x4A => self.lsr_acc(),
+            
+            0x46 | 0x56 | 0x4E | 0x5E => self.lsr(&opcode.mode),
 
-                // SED
-                0xF8 => self.status.insert(CPUFlags::DECIMAL),
+            // ORA
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.ora(&opcode.mode),
 
-                // SEI
-                0x78 => self.status.insert(CPUFlags::INTERRUPT),
+            // PHA
+            0x48 => self.push_to_stack(self.register_a),
 
-                // SRE
-                0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => {
-                    self.lsr(&opcode.mode);
-                    self.eor(&opcode.mode);
-                }
+            // PHP
+            0x08 => self.php(),
 
-                // STA
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+            // PLA
+            0x68 => self.pla(),
 
-                // STX
-                0x86 | 0x96 | 0x8E => self.stx(&opcode.mode),
+            // PLP
+            0x28 => self.plp(),
 
-                // STY
-                0x84 | 0x94 | 0x8C => self.sty(&opcode.mode),
+            // RLA
+            0x27 | 0x37 | 0x2F | 0x3F | 0x3B | 0x23 | 0x33 => {
+                self.rol(&opcode.mode);
+                self.and(&opcode.mode);
+            }
 
-                // TAX
-                0xAA => self.tax(),
+            // RRA
+            0x67 | 0x77 | 0x6F | 0x7F | 0x7B | 0x63 | 0x73 => {
+                self.ror(&opcode.mode);
+                self.adc(&opcode.mode);
+            }
 
-                // TAY
-                0xA8 => self.tay(),
-                
-                // TSX
-                0xBA => self.tsx(),
+            // ROL ACCUMULATOR
+            0x2A => {
+                self.register_a = self.rotate_left(self.register_a);
+                self.status.set(CPUFlags::ZERO, self.register_a == 0);
+            },
+
+            // ROL
+            0x26 | 0x36 | 0x2E | 0x3E => self.rol(&opcode.mode),
+
+            // ROR ACCUMULATOR
+            0x6A => {
+                self.register_a = self.rotate_right(self.register_a);
+                self.status.set(CPUFlags::ZERO, self.register_a == 0);
+            },
+             
+            // ROR
+            0x66 | 0x76 | 0x6E | 0x7E => self.ror(&opcode.mode),
+
+            // RTI
+            0x40 => self.rti(),
+
+            // RTS
+            0x60 => self.rts(),
+
+            // SBC
+            0xEB | 0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.sbc(&opcode.mode),
+
+            // SLO
+            0x07 | 0x17 | 0x0F | 0x1F | 0x1B | 0x03 | 0x13 => {
+                self.asl(&opcode.mode);
+                self.ora(&opcode.mode);
+            }
 
-                // TXA
-                0x8A => self.txa(),
+            // SEC
+            0x38 => self.status.insert(CPUFlags::CARRY),
 
-                // TXS
-                0x9A  => self.register_s = self.register_x, // No flags needed to update
+            // SED
+            0xF8 => self.status.insert(CPUFlags::DECIMAL),
 
-                // TYA
-                0x98  => self.tya(),
+            // SEI
+            0x78 => self.status.insert(CPUFlags::INTERRUPT),
 
-                
-                _ => todo!("")
+            // SRE
+            0x47 | 0x57 | 0x4F | 0x5F | 0x5B | 0x43 | 0x53 => {
+                self.lsr(&opcode.mode);
+                self.eor(&opcode.mode);
             }
+
+            // STA
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.sta(&opcode.mode),
+
+            // STX
+            0x86 | 0x96 | 0x8E => self.stx(&opcode.mode),
+
+            // STY
+            0x84 | 0x94 | 0x8C => self.sty(&opcode.mode),
+
+            // TAX
+            0xAA => self.tax(),
+
+            // TAY
+            0xA8 => self.tay(),
             
-            self.bus.tick(opcode.cycles);
-           
-            self.program_counter += opcode.len as u16 - 1;
+            // TSX
+            0xBA => self.tsx(),
+
+            // TXA
+            0x8A => self.txa(),
+
+            // TXS
+            0x9A  => self.register_s = self.register_x, // No flags needed to update, unlike TSX
+
+            // TYA
+            0x98  => self.tya(),
+
+            
+            _ => {
+                self.program_counter -= 1;
+                return Err(CpuError::UnknownOpcode(opcode.code));
+            }
         }
 
+        if self.unsupported_mode {
+            self.program_counter = start;
+            return Err(CpuError::UnsupportedMode(code));
+        }
+        if let Some(addr) = self.bus.take_rom_write() {
+            if self.strict_mode {
+                self.program_counter = start;
+                return Err(CpuError::RomWrite(addr));
+            }
+        }
+        
+        self.bus.tick(opcode.cycles);
+        self.bus.start_dma_stall();
+       
+        self.program_counter += opcode.len as u16 - 1;
+        Ok(true)
     }
 
 }
@@ -1008,6 +1320,24 @@ mod test {
         assert!(cpu.status.contains(CPUFlags::CARRY));
     }
 
+    #[test]
+    fn test_rmw_double_write() {
+        // LDA #$10 STA $2003 INC $2004 LDA #$11 STA $2003 LDA $2004 BRK
+        // Every OAMDATA write bumps OAMADDR, so a double write lands the result at 0x11
+        let program = vec![0xa9, 0x10, 0x8d, 0x03, 0x20, 0xee, 0x04, 0x20, 0xa9, 0x11, 0x8d, 0x03, 0x20, 0xad, 0x04, 0x20, 0x00];
+
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(program.clone());
+        assert_eq!(cpu.register_a, 0x00);
+
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.rmw_double_write = true;
+        cpu.load_and_run(program);
+        assert_eq!(cpu.register_a, 0x01);
+    }
+
     #[test]
     fn test_bit_clear_all() {
         let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
@@ -1126,6 +1456,462 @@ mod test {
         assert!(cpu.status.contains(CPUFlags::NEGATIVE));
     }
 
+    #[test]
+    fn test_cmp_negative_result() {
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xa9, 0x00, 0xc9, 0x01, 0x00]); // LDA #$00 CMP #$01 BRK, 0x00 - 0x01 = 0xFF
+
+        assert!(!cpu.status.contains(CPUFlags::CARRY));
+        assert!(!cpu.status.contains(CPUFlags::ZERO));
+        assert!(cpu.status.contains(CPUFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_pending_interrupts_nmi() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.bus.mem_write(0x2000, 0b1000_0000); // Enable NMI on vblank
+        for _ in 0..13697 {
+            cpu.bus.tick(2); // Run the PPU up to scanline 241
+        }
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.pending_interrupts(), InterruptState { nmi: true, irq: false });
+
+        let mut pending = vec![];
+        cpu.run_with_callback(|cpu| {
+            pending.push(cpu.pending_interrupts());
+        });
+        assert_eq!(pending[0], InterruptState { nmi: false, irq: false });
+    }
+
+    #[test]
+    fn test_try_step() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xa9, 0x05, 0x1a, 0x00]); // LDA #$05 *NOP BRK
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.try_step(), Ok(2));
+        assert_eq!(cpu.register_a, 0x05);
+
+        cpu.strict_mode = true;
+        assert_eq!(cpu.try_step(), Err(CpuError::IllegalOpcode(0x1a)));
+        assert_eq!(cpu.program_counter, 0x0602);
+
+        cpu.strict_mode = false;
+        assert_eq!(cpu.try_step(), Ok(2));
+        assert_eq!(cpu.try_step(), Ok(0));
+    }
+
+    #[test]
+    fn test_try_step_rom_write() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0x8d, 0x00, 0x80, 0x8d, 0x00, 0x80]); // STA $8000 STA $8000
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.try_step(), Ok(4)); // Dropped
+
+        cpu.strict_mode = true;
+        assert_eq!(cpu.try_step(), Err(CpuError::RomWrite(0x8000)));
+        assert_eq!(cpu.program_counter, 0x0603);
+    }
+
+    #[test]
+    fn test_unsupported_mode_does_not_panic() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        assert_eq!(cpu.get_operand_address_from_base(&AddressingMode::NoneAddressing, 0x0600), 0);
+        assert!(cpu.unsupported_mode);
+    }
+
+    #[test]
+    fn test_set_illegal_opcode_enabled() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.mem_write(0x10, 0x42);
+        cpu.load(vec![0xa7, 0x10, 0x07, 0x10]); // *LAX $10 *SLO $10
+        cpu.program_counter = 0x0600;
+
+        cpu.strict_mode = true;
+        cpu.set_illegal_opcode_enabled(0xa7, true);
+        cpu.set_illegal_opcode_enabled(0x07, false);
+        assert!(cpu.try_step().is_ok());
+        assert_eq!(cpu.register_a, 0x42);
+        assert_eq!(cpu.register_x, 0x42);
+
+        // Disabled even with strict mode off
+        cpu.strict_mode = false;
+        assert_eq!(cpu.try_step(), Err(CpuError::IllegalOpcode(0x07)));
+        assert_eq!(cpu.mem_read(0x10), 0x42);
+    }
+
+    #[test]
+    fn test_step_n() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xa9, 0x05, 0xaa, 0xe8, 0xe8, 0x00]); // LDA #$05 TAX INX INX BRK
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.step_n(3), 3);
+        assert_eq!(cpu.program_counter, 0x0604);
+        assert_eq!(cpu.register_x, 0x06);
+
+        // Stops at BRK
+        assert_eq!(cpu.step_n(5), 1);
+        assert_eq!(cpu.register_x, 0x07);
+    }
+
+    #[test]
+    fn test_last_instruction() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xa9, 0x05, 0x00]); // LDA #$05 BRK
+        cpu.program_counter = 0x0600;
+        assert_eq!(cpu.last_instruction(), None);
+
+        cpu.try_step().unwrap();
+        assert_eq!(cpu.last_instruction(), Some((0xa9, "LDA")));
+    }
+
+    #[test]
+    fn test_oam_dma_stall() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        // LDA #$02 STA $4014 LDA #$02 STA $4014 BRK
+        cpu.load(vec![0xa9, 0x02, 0x8d, 0x14, 0x40, 0xa9, 0x02, 0x8d, 0x14, 0x40, 0x00]);
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.try_step(), Ok(2));
+        assert_eq!(cpu.try_step(), Ok(4));
+        assert!(cpu.bus.dma_in_progress()); // Starts on cycle 6, the next step pays for it
+
+        assert_eq!(cpu.try_step(), Ok(513 + 2));
+        assert!(!cpu.bus.dma_in_progress());
+
+        assert_eq!(cpu.try_step(), Ok(4));
+        assert!(cpu.bus.dma_in_progress()); // Starts on cycle 525, one extra to align
+        assert_eq!(cpu.try_step(), Ok(0)); // BRK
+        assert!(!cpu.bus.dma_in_progress());
+        assert_eq!(cpu.bus.cycles, 2 + 4 + 513 + 2 + 4 + 514);
+    }
+
+    #[test]
+    fn test_try_step_unknown_opcode() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0x02]); // Not in the opcode table
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.try_step(), Err(CpuError::UnknownOpcode(0x02)));
+    }
+
+    #[test]
+    fn test_run_with_opcode_histogram() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xa9, 0x01, 0xe8, 0xe8, 0x00]); // LDA #$01 INX INX BRK
+        cpu.program_counter = 0x0600;
+
+        let histogram = cpu.run_with_opcode_histogram();
+        assert_eq!(histogram.len(), 3);
+        assert_eq!(histogram["LDA"], 1);
+        assert_eq!(histogram["INX"], 2);
+        assert_eq!(histogram["BRK"], 1);
+    }
+
+    #[test]
+    fn test_stack_wraps_within_page_1() {
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x00FF, 0xAA); // Either side of the stack page
+        cpu.mem_write(0x0200, 0xBB);
+
+        // 0xFD down through 0x00 then wraps around to 0xFF
+        for i in 0..0x100 + 2 {
+            cpu.push_to_stack(i as u8);
+        }
+        assert_eq!(cpu.register_s, 0xFB);
+        assert_eq!(cpu.mem_read(0x01FD), 0x00);
+        assert_eq!(cpu.mem_read(0x0100), 0xFD);
+        assert_eq!(cpu.mem_read(0x01FF), 0xFE);
+        assert_eq!(cpu.mem_read(0x01FC), 0x01); // Overwritten by the final push
+        assert_eq!(cpu.mem_read(0x00FF), 0xAA);
+        assert_eq!(cpu.mem_read(0x0200), 0xBB);
+
+        // Underflow back past 0xFF
+        cpu.register_s = 0xFE;
+        assert_eq!(cpu.pop_stack(), 0xFE); // 0x01FF
+        assert_eq!(cpu.pop_stack(), 0xFD); // S wraps to 0x00, read from 0x0100 not 0x0200
+        assert_eq!(cpu.register_s, 0x00);
+    }
+
+    #[test]
+    fn test_txs_leaves_flags() {
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xa2, 0x80, 0xa9, 0x01, 0x9a, 0x00]); // LDX #$80 LDA #$01 TXS BRK
+
+        assert_eq!(cpu.register_s, 0x80);
+        assert!(!cpu.status.contains(CPUFlags::NEGATIVE));
+        assert!(!cpu.status.contains(CPUFlags::ZERO));
+    }
+
+    #[test]
+    fn test_tsx_updates_flags() {
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.load_and_run(vec![0xa9, 0x01, 0xba, 0x00]); // LDA #$01 TSX BRK
+
+        assert_eq!(cpu.register_x, 0xFD);
+        assert!(cpu.status.contains(CPUFlags::NEGATIVE));
+    }
+
+    #[test]
+    fn test_detect_tight_loop() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0x4c, 0x00, 0x06]); // JMP $0600
+        cpu.program_counter = 0x0600;
+
+        assert!(cpu.detect_tight_loop(100));
+        assert_eq!(cpu.program_counter, 0x0600);
+    }
+
+    #[test]
+    fn test_detect_tight_loop_straight_line() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xe8; 20]); // INX x20
+        cpu.program_counter = 0x0600;
+
+        assert!(!cpu.detect_tight_loop(15));
+    }
+
+    #[test]
+    fn test_run_until_vblank() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0x4c, 0x00, 0x06]); // JMP $0600
+        cpu.program_counter = 0x0600;
+
+        let count = cpu.run_until_vblank();
+        assert!(count > 0);
+        assert!(cpu.bus.ppu().scanlines >= 241);
+        assert!(cpu.bus.ppu().status.in_vertical_blank());
+    }
+
+    #[test]
+    fn test_sbc_overflow() {
+        // (A, operand, result, overflow, carry) for each combination of signs
+        let cases = [
+            (0x50, 0xB0, 0xA0, true, false),  // positive - negative = negative
+            (0x50, 0x30, 0x20, false, true),  // positive - positive
+            (0xD0, 0x70, 0x60, true, true),   // negative - positive = positive
+            (0xD0, 0xF0, 0xE0, false, false), // negative - negative
+        ];
+
+        for (a, operand, result, overflow, carry) in cases {
+            let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+            let mut cpu = CPU::new(bus);
+            cpu.load_and_run(vec![0x38, 0xa9, a, 0xe9, operand, 0x00]); // SEC LDA #a SBC #operand BRK
+
+            assert_eq!(cpu.register_a, result, "{:02X} - {:02X}", a, operand);
+            assert_eq!(cpu.status.contains(CPUFlags::OVERFLOW), overflow, "{:02X} - {:02X}", a, operand);
+            assert_eq!(cpu.status.contains(CPUFlags::CARRY), carry, "{:02X} - {:02X}", a, operand);
+        }
+    }
+
+    #[test]
+    fn test_page_cross_cycles() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![
+            0xa2, 0x01,       // LDX #$01
+            0xfe, 0xff, 0x12, // INC $12FF,X
+            0xbd, 0xff, 0x12, // LDA $12FF,X
+            0xbd, 0x00, 0x12, // LDA $1200,X
+            0x9d, 0xff, 0x12, // STA $12FF,X
+        ]);
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.try_step(), Ok(2));
+        assert_eq!(cpu.try_step(), Ok(7)); // RMW never adds the penalty
+        assert_eq!(cpu.try_step(), Ok(5));
+        assert_eq!(cpu.try_step(), Ok(4));
+        assert_eq!(cpu.try_step(), Ok(5)); // Stores always take 5
+    }
+
+    #[test]
+    fn test_absolute_read_off_pc_page_costs_no_penalty() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xad, 0x00, 0x07, 0xa5, 0x10]); // LDA $0700 LDA $10
+        cpu.program_counter = 0x0600;
+
+        // Operand is on another page than the instruction, but nothing is indexed
+        assert_eq!(cpu.try_step(), Ok(4));
+        assert_eq!(cpu.try_step(), Ok(3));
+    }
+
+    #[test]
+    fn test_trigger_nmi() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom()); // NMI vector at 0xFFFA reads 0x0101
+        cpu.mem_write(0x0101, 0xea); // NOP
+        cpu.program_counter = 0x0600;
+
+        cpu.bus.trigger_nmi();
+        assert!(cpu.pending_interrupts().nmi);
+        assert!(cpu.try_step().is_ok());
+        assert_eq!(cpu.program_counter, 0x0102);
+        assert_eq!(cpu.mem_read_u16(0x01FC), 0x0600); // Return address
+        assert!(!cpu.pending_interrupts().nmi);
+    }
+
+    #[test]
+    fn test_nmi_pushes_status_with_b_clear() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom()); // NMI vector at 0xFFFA reads 0x0101
+        // NMI handler: PLA PHA AND #$10 STA $10
+        for (i, byte) in [0x68, 0x48, 0x29, 0x10, 0x85, 0x10].iter().enumerate() {
+            cpu.mem_write(0x0101 + i as u16, *byte);
+        }
+        cpu.load(vec![0x08, 0x68, 0x29, 0x10, 0x85, 0x11, 0x00]); // PHP PLA AND #$10 STA $11 BRK
+        cpu.program_counter = 0x0600;
+        cpu.run();
+        assert_eq!(cpu.mem_read(0x11), 0x10); // Software pushes set B
+
+        cpu.mem_write(0x10, 0xff);
+        cpu.bus.trigger_nmi();
+        cpu.step_n(4);
+        assert_eq!(cpu.program_counter, 0x0107);
+        assert_eq!(cpu.mem_read(0x10), 0x00); // Hardware interrupts clear it
+    }
+
+    #[test]
+    fn test_run_until_write() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        // LDA #$01 STA $11 LDA #$42 STA $10 INX BRK
+        cpu.load(vec![0xa9, 0x01, 0x85, 0x11, 0xa9, 0x42, 0x85, 0x10, 0xe8, 0x00]);
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.run_until_write(0x0010), Some(0x42));
+        assert_eq!(cpu.program_counter, 0x0608); // Stopped before INX
+        assert_eq!(cpu.register_x, 0);
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut rom = cartridge::test::test_rom();
+        let len = rom.prg_rom.len();
+        rom.prg_rom[len - 6..].copy_from_slice(&[0x00, 0x90, 0x00, 0x80, 0x00, 0xA0]);
+
+        let mut cpu = CPU::from_rom(rom);
+        assert_eq!(cpu.vectors(), (0x9000, 0x8000, 0xA000));
+    }
+
+    #[test]
+    fn test_run_until_write_halts() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xa9, 0x42, 0x85, 0x11, 0x00]); // LDA #$42 STA $11 BRK
+        cpu.program_counter = 0x0600;
+
+        assert_eq!(cpu.run_until_write(0x0010), None);
+        assert_eq!(cpu.program_counter, 0x0605);
+        assert_eq!(cpu.bus.take_watch_hit(), None);
+    }
+
+    #[test]
+    fn test_from_rom() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load_and_run(vec![0xa9, 0x05, 0xaa, 0xe8, 0x00]); // LDA #$05 TAX INX BRK
+        assert_eq!(cpu.register_x, 0x06);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap_bug() {
+        let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
+        let mut cpu = CPU::new(bus);
+        cpu.mem_write(0x02FF, 0x34); // Low byte of the pointer
+        cpu.mem_write(0x0200, 0x12); // High byte the 6502 actually reads
+        cpu.mem_write(0x0300, 0x56); // High byte a correct CPU would read
+
+        assert_eq!(cpu.calculate_jmp_indirect_bug(0x02FF), 0x1234);
+
+        // Off the page boundary the pointer is read normally
+        cpu.mem_write(0x0280, 0x78);
+        cpu.mem_write(0x0281, 0x9A);
+        assert_eq!(cpu.calculate_jmp_indirect_bug(0x0280), 0x9A78);
+    }
+
+    #[test]
+    fn test_dummy_read_on_page_cross() {
+        for dummy_read in [false, true] {
+            let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+            cpu.dummy_read = dummy_read;
+            while !cpu.bus.ppu().status.in_vertical_blank() {
+                cpu.bus.tick(1);
+            }
+
+            // LDA $3FFF,X with X=3 reads 0x4002, the dummy read hits 0x3F02 which mirrors PPUSTATUS
+            cpu.load(vec![0xa2, 0x03, 0xbd, 0xff, 0x3f]);
+            cpu.program_counter = 0x0600;
+            assert_eq!(cpu.step_n(2), 2);
+
+            assert_eq!(cpu.bus.ppu().status.in_vertical_blank(), !dummy_read);
+        }
+    }
+
+    #[test]
+    fn test_run_until_frame() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0x4c, 0x00, 0x06]); // JMP $0600
+        cpu.program_counter = 0x0600;
+
+        assert!(cpu.run_until_frame());
+        assert_eq!(cpu.bus.ppu().frames, 1);
+        assert_eq!(cpu.bus.ppu().scanlines, 0); // Wrapped back to the top
+
+        assert!(cpu.run_until_frame());
+        assert_eq!(cpu.bus.ppu().frames, 2);
+
+        cpu.mem_write(0x0600, 0x00); // BRK
+        assert!(!cpu.run_until_frame());
+    }
+
+    #[test]
+    fn test_new_flat() {
+        let mut image = [0; 0x10000];
+        image[0xFFFC] = 0x00; // Reset vector 0x9000
+        image[0xFFFD] = 0x90;
+        image[0x4000] = 0x55;
+        // LDA $4000 STA $8000 BRK
+        image[0x9000..0x9007].copy_from_slice(&[0xad, 0x00, 0x40, 0x8d, 0x00, 0x80, 0x00]);
+
+        let mut cpu = CPU::new_flat(&image);
+        assert_eq!(cpu.program_counter, 0x9000);
+        cpu.run();
+        assert_eq!(cpu.register_a, 0x55);
+        assert_eq!(cpu.mem_read(0x8000), 0x55); // No ROM, writable like the rest
+        assert_eq!(cpu.bus.peek(0x4000), 0x55);
+    }
+
+    #[test]
+    fn test_load_ram() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+
+        let mut ram = [0; 0x800];
+        ram[0x10] = 0x55;
+        ram[0x0600..0x0605].copy_from_slice(&[0xa5, 0x10, 0xaa, 0xe8, 0x00]); // LDA $10 TAX INX BRK
+        cpu.load_ram(&ram);
+
+        cpu.program_counter = 0x0600;
+        cpu.run();
+        assert_eq!(cpu.register_a, 0x55);
+        assert_eq!(cpu.register_x, 0x56);
+    }
+
+    #[test]
+    fn test_run_with_trace_filter() {
+        let mut cpu = CPU::from_rom(cartridge::test::test_rom());
+        cpu.load(vec![0xa9, 0x01, 0x20, 0x10, 0x06, 0x00]); // LDA #$01 JSR $0610 BRK
+        cpu.mem_write(0x0610, 0xe8); // INX
+        cpu.mem_write(0x0611, 0x60); // RTS
+        cpu.program_counter = 0x0600;
+
+        let mut traced = vec![];
+        cpu.run_with_trace_filter(0x0610..0x0620, |cpu| {
+            traced.push(cpu.program_counter);
+        });
+        assert_eq!(traced, vec![0x0610, 0x0611]);
+        assert_eq!(cpu.register_x, 0x01);
+    }
+
     #[test]
     fn test_0xa9_lda_immediate_load_data() {
          let bus = Bus::new(cartridge::test::test_rom(), |_, _|{});
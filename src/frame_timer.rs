@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+pub const NTSC_FPS: f64 = 60.0988;
+
+// Further behind than this (debugger pause, slow host) and the timer resyncs to now
+// rather than rushing frames to catch up
+const MAX_FRAMES_BEHIND: u32 = 3;
+
+// Sleeps out whatever is left of each frame's time budget so emulation runs at the target rate
+pub struct FrameTimer {
+    frame_duration: Duration,
+    frame_start: Instant,
+}
+
+impl FrameTimer {
+    pub fn new(fps: f64) -> Self {
+        FrameTimer {
+            frame_duration: Duration::from_secs_f64(1.0 / fps),
+            frame_start: Instant::now(),
+        }
+    }
+
+    // How long to sleep at `now` to fill out the current frame, zero when it ran over
+    pub fn sleep_duration(&self, now: Instant) -> Duration {
+        self.frame_duration.saturating_sub(now.saturating_duration_since(self.frame_start))
+    }
+
+    pub fn wait_for_next_frame(&mut self) {
+        let sleep = self.sleep_duration(Instant::now());
+        if !sleep.is_zero() {
+            spin_sleep::sleep(sleep);
+        }
+        self.advance(Instant::now());
+    }
+
+    // Step the frame start by exactly one budget so oversleeping doesn't carry into
+    // the next frame and drag the rate down
+    fn advance(&mut self, now: Instant) {
+        self.frame_start += self.frame_duration;
+        if now.saturating_duration_since(self.frame_start) > self.frame_duration * MAX_FRAMES_BEHIND {
+            self.frame_start = now;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sleep_duration() {
+        let timer = FrameTimer::new(60.0);
+        let budget = Duration::from_secs_f64(1.0 / 60.0);
+
+        let took = Duration::from_millis(5);
+        assert_eq!(timer.sleep_duration(timer.frame_start + took), budget - took);
+
+        // Frame ran over budget
+        assert_eq!(timer.sleep_duration(timer.frame_start + Duration::from_millis(20)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_advance_keeps_frame_boundaries() {
+        let mut timer = FrameTimer::new(60.0);
+        let start = timer.frame_start;
+
+        // Overslept by 2ms, the next frame still starts on the boundary
+        timer.advance(start + timer.frame_duration + Duration::from_millis(2));
+        assert_eq!(timer.frame_start, start + timer.frame_duration);
+
+        // Way behind, resync
+        let now = timer.frame_start + Duration::from_secs(1);
+        timer.advance(now);
+        assert_eq!(timer.frame_start, now);
+    }
+
+    #[test]
+    fn test_wait_for_next_frame() {
+        let mut timer = FrameTimer::new(NTSC_FPS);
+        let start = timer.frame_start;
+        timer.wait_for_next_frame();
+        assert!(timer.frame_start.duration_since(start) >= timer.frame_duration);
+    }
+}
@@ -16,6 +16,7 @@ pub struct Joypad {
     strobe: bool,
     button_index: u8,
     button_status: JoypadButton,
+    filter_opposing: bool,
  }
  
  impl Joypad {
@@ -24,12 +25,40 @@ pub struct Joypad {
             strobe: false,
             button_index: 0,
             button_status: JoypadButton::from_bits_truncate(0),
+            filter_opposing: false,
         }
     }
 
+    // Left+Right and Up+Down can't happen on a real d-pad without wear, some games crash on it.
+    // When on, Left is dropped while Right is held and Up while Down is held
+    pub fn set_filter_opposing(&mut self, filter: bool) {
+        self.filter_opposing = filter;
+    }
+
+    fn reported_status(&self) -> JoypadButton {
+        let mut status = self.button_status;
+        if self.filter_opposing {
+            if status.contains(JoypadButton::RIGHT) {
+                status.remove(JoypadButton::LEFT);
+            }
+            if status.contains(JoypadButton::DOWN) {
+                status.remove(JoypadButton::UP);
+            }
+        }
+        status
+    }
+
     pub fn set_button_pressed_status(&mut self, button: JoypadButton, status: bool) {
         self.button_status.set(button, status);
     }
+
+    pub fn buttons(&self) -> JoypadButton {
+        self.reported_status()
+    }
+
+    pub fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.button_status = buttons;
+    }
     
     pub fn write(&mut self, data: u8) {
         self.strobe = data & 1 == 1;
@@ -42,10 +71,36 @@ pub struct Joypad {
         if self.button_index > 7 {
             return 1;
         }
-        let response = (self.button_status.bits & (1 << self.button_index)) >> self.button_index;
+        let response = (self.reported_status().bits & (1 << self.button_index)) >> self.button_index;
         if !self.strobe && self.button_index <= 7 {
             self.button_index += 1;
         }
         response
     }
- }
\ No newline at end of file
+ }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_all(joypad: &mut Joypad) -> u8 {
+        joypad.write(1);
+        joypad.write(0);
+        (0..8).fold(0, |buttons, i| buttons | joypad.read() << i)
+    }
+
+    #[test]
+    fn test_filter_opposing() {
+        let mut joypad = Joypad::new();
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, true);
+        assert_eq!(read_all(&mut joypad), 0b1100_0000);
+
+        joypad.set_filter_opposing(true);
+        assert_eq!(read_all(&mut joypad), JoypadButton::RIGHT.bits());
+
+        joypad.set_button_pressed_status(JoypadButton::UP, true);
+        joypad.set_button_pressed_status(JoypadButton::DOWN, true);
+        assert_eq!(joypad.buttons(), JoypadButton::RIGHT | JoypadButton::DOWN);
+    }
+}
@@ -1 +1,2 @@
-pub mod joypad;
\ No newline at end of file
+pub mod joypad;
+pub mod replay;
\ No newline at end of file
@@ -0,0 +1,126 @@
+use super::joypad::{Joypad, JoypadButton};
+
+// A single controller state captured on a given frame
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct InputEvent {
+    pub frame: u32,
+    pub player: u8,
+    pub buttons: u8,
+}
+
+#[derive(Default)]
+pub struct InputRecorder {
+    events: Vec<InputEvent>,
+}
+
+impl InputRecorder {
+    pub fn new() -> Self {
+        InputRecorder { events: vec![] }
+    }
+
+    // Call once per frame for each player, e.g. from the gameloop callback
+    pub fn record(&mut self, frame: u32, player: u8, joypad: &Joypad) {
+        self.events.push(InputEvent {
+            frame,
+            player,
+            buttons: joypad.buttons().bits(),
+        });
+    }
+
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    // File format is one "frame player buttons" line per event, buttons in hex
+    // e.g. "120 1 09" = frame 120, player 1, START + A
+    pub fn serialize(&self) -> String {
+        self.events
+            .iter()
+            .map(|e| format!("{} {} {:02X}\n", e.frame, e.player, e.buttons))
+            .collect()
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        std::fs::write(path, self.serialize())
+    }
+}
+
+pub struct InputPlayback {
+    events: Vec<InputEvent>,
+}
+
+impl InputPlayback {
+    pub fn parse(data: &str) -> Result<InputPlayback, String> {
+        let mut events = vec![];
+        for (i, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(format!("Malformed input event on line {}", i + 1));
+            }
+
+            let frame = fields[0].parse::<u32>();
+            let player = fields[1].parse::<u8>();
+            let buttons = u8::from_str_radix(fields[2], 16);
+            match (frame, player, buttons) {
+                (Ok(frame), Ok(player), Ok(buttons)) => events.push(InputEvent { frame, player, buttons }),
+                _ => return Err(format!("Malformed input event on line {}", i + 1)),
+            }
+        }
+
+        Ok(InputPlayback { events })
+    }
+
+    pub fn load(path: &str) -> Result<InputPlayback, String> {
+        let data = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        InputPlayback::parse(&data)
+    }
+
+    // Put the recorded buttons for this frame onto the joypad, leaves it untouched if nothing was recorded
+    pub fn apply(&self, frame: u32, player: u8, joypad: &mut Joypad) {
+        if let Some(event) = self.events.iter().find(|e| e.frame == frame && e.player == player) {
+            joypad.set_buttons(JoypadButton::from_bits_truncate(event.buttons));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record_and_replay() {
+        let presses = [
+            JoypadButton::START,
+            JoypadButton::START | JoypadButton::BUTTON_A,
+            JoypadButton::empty(),
+            JoypadButton::LEFT | JoypadButton::BUTTON_B,
+            JoypadButton::RIGHT,
+        ];
+
+        let mut recorder = InputRecorder::new();
+        let mut joypad = Joypad::new();
+        for (frame, buttons) in presses.iter().enumerate() {
+            joypad.set_buttons(*buttons);
+            recorder.record(frame as u32, 1, &joypad);
+        }
+        assert_eq!(recorder.events().len(), 5);
+
+        let playback = InputPlayback::parse(&recorder.serialize()).unwrap();
+        let mut joypad = Joypad::new();
+        for (frame, buttons) in presses.iter().enumerate() {
+            playback.apply(frame as u32, 1, &mut joypad);
+            assert_eq!(joypad.buttons(), *buttons);
+        }
+    }
+
+    #[test]
+    fn test_replay_malformed() {
+        let playback = InputPlayback::parse("0 1 09\n1 1 ZZ");
+        assert_eq!(playback.err(), Some("Malformed input event on line 2".to_string()));
+    }
+}
@@ -8,6 +8,7 @@ pub mod trace;
 pub mod ppu;
 pub mod render;
 pub mod input;
+pub mod frame_timer;
 
 #[macro_use]
 extern crate lazy_static;
@@ -16,7 +17,7 @@ extern crate bitflags;
 
 use std::collections::HashMap;
 
-use render::frame::Frame;
+use render::{frame::Frame, SCREEN_WIDTH, SCREEN_HEIGHT};
 use sdl2::{pixels::PixelFormatEnum, event::Event, keyboard::Keycode};
 use crate::{cpu::CPU, bus::Bus, cartridge::Rom, ppu::ppu::PPU, input::joypad::{Joypad, self}};
 
@@ -50,7 +51,7 @@ fn main() {
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-                    .window("Snake", (256 * 3) as u32, (240 * 3) as u32)
+                    .window("Snake", (SCREEN_WIDTH * 3) as u32, (SCREEN_HEIGHT * 3) as u32)
                     .position_centered()
                     .build().unwrap();
 
@@ -60,7 +61,7 @@ fn main() {
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-                        .create_texture_target(PixelFormatEnum::RGB24, 256, 240).unwrap();
+                        .create_texture_target(PixelFormatEnum::RGB24, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).unwrap();
 
 
     let bytes: Vec<u8> = std::fs::read("m.nes").unwrap();
@@ -83,7 +84,7 @@ fn main() {
 
     let bus = Bus::new(rom, move |ppu: &PPU, joypad: &mut Joypad| {
         render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+        texture.update(None, &frame.data, SCREEN_WIDTH * 3).unwrap();
 
         canvas.copy(&texture, None, None).unwrap();
 
@@ -513,4 +513,24 @@ lazy_static! {
         }
         map
     };
+}
+
+pub fn lookup(code: u8) -> Option<&'static OpCode> {
+    OPCODES_MAP.get(&code).copied()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lookup() {
+        let opcode = lookup(0xA9).unwrap();
+        assert_eq!(opcode.mnemonic, "LDA");
+        assert_eq!(opcode.len, 2);
+        assert_eq!(opcode.cycles, 2);
+
+        assert!(lookup(0x02).is_none()); // KIL isn't implemented
+    }
 }
\ No newline at end of file
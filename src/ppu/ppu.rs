@@ -1,7 +1,29 @@
 use crate::cartridge::Mirroring;
+use crate::render::{self, SCREEN_WIDTH};
 
 use super::registers::{address::AddrRegister, control::ControlRegister, mask::MaskRegister, status::StatusRegister, scroll::ScrollRegister};
 
+// 29658 CPU cycles after power on before PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR take writes
+const WARM_UP_CYCLES: usize = 29658 * 3;
+
+// The open bus latch fades out after roughly 600ms without a register write
+const OPEN_BUS_DECAY_FRAMES: usize = 36;
+
+
+// Snapshot of PPU state for debuggers
+#[derive(Debug, PartialEq)]
+pub struct PpuDebugState {
+    pub scanline: u16,
+    pub cycle: usize,
+    pub vram_addr: u16,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    pub control: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+    pub nmi_pending: bool,
+}
 
 pub struct PPU {
     pub chr_rom: Vec<u8>,
@@ -14,8 +36,19 @@ pub struct PPU {
 
     pub scanlines: u16,
     pub cycles: usize,
+    pub frames: usize,
     pub nmi_interrupt: Option<u8>,
 
+    // Accuracy: ignore register writes during the power on warm up
+    pub warm_up: bool,
+    warm_up_cycles: usize,
+
+    // Last value written to a PPU register, read back from the write only registers
+    // and the unused PPUSTATUS bits. Cleared after open_bus_decay_frames with no writes
+    pub open_bus: u8,
+    pub open_bus_decay_frames: usize,
+    open_bus_refreshed: usize,
+
     // REGISTERS
     // =====================
     pub addr: AddrRegister,
@@ -48,14 +81,35 @@ impl PPU {
 
             scanlines: 0,
             cycles: 0,
+            frames: 0,
             nmi_interrupt: None,
 
+            warm_up: false,
+            warm_up_cycles: 0,
+
+            open_bus: 0,
+            open_bus_decay_frames: OPEN_BUS_DECAY_FRAMES,
+            open_bus_refreshed: 0,
         }
     }
 
+    // OAM is DRAM and doesn't power up as zeros on hardware, some test ROMs rely on that
+    pub fn new_with_oam_pattern(chr_rom: Vec<u8>, mirroring: Mirroring, pattern: u8) -> Self {
+        let mut ppu = PPU::new(chr_rom, mirroring);
+        ppu.oam_data = [pattern; 256];
+        ppu
+    }
+
     pub fn tick(&mut self, cycles: u8) -> bool {
         self.cycles += cycles as usize;
+        if self.warm_up_cycles < WARM_UP_CYCLES {
+            self.warm_up_cycles += cycles as usize;
+        }
         if self.cycles >= 341 { // Every scanline lasts for 341 cycles
+            if self.is_sprite_0_hit() {
+                self.status.set_sprite_0_hit(true);
+            }
+
             self.cycles = self.cycles - 341;
             self.scanlines += 1;
 
@@ -69,6 +123,10 @@ impl PPU {
 
             if self.scanlines >= 262 {
                 self.scanlines = 0;
+                self.frames += 1;
+                if self.frames - self.open_bus_refreshed >= self.open_bus_decay_frames {
+                    self.open_bus = 0;
+                }
                 self.nmi_interrupt = None;
                 self.status.set_sprite_0_hit(false);
                 self.status.set_vertical_blank(false);
@@ -78,6 +136,48 @@ impl PPU {
         return false;
     }
 
+    // Vblank, sprite 0 hit and the scanline counter only change when a scanline ends,
+    // so a scheduler can skip straight to the end of the current one. Clamped to what one
+    // tick can take, early in a scanline it takes two skips to reach the end
+    pub fn cycles_until_next_event(&self) -> u8 {
+        341usize.saturating_sub(self.cycles).min(u8::MAX as usize) as u8
+    }
+
+    // x, y, width, height of sprite 0 for debug overlays
+    pub fn sprite_0_bounds(&self) -> (u8, u8, u8, u8) {
+        (self.oam_data[3], self.oam_data[0], 8, self.control.sprite_size())
+    }
+
+    // First and last scanline an OAM sprite covers, 16 lines tall in 8x16 mode
+    pub fn sprite_scanline_range(&self, oam_index: usize) -> (u16, u16) {
+        let y = self.oam_data[oam_index * 4] as u16;
+        (y, y + self.control.sprite_size() as u16 - 1)
+    }
+
+    // Sprite 0 hit needs an opaque sprite 0 pixel on top of an opaque background pixel, with both
+    // being rendered. Never at x 255, or in the leftmost 8 pixels while either layer is clipped there.
+    // Checked as a scanline ends, so the whole line (and sprite 0's x) has already been drawn
+    fn is_sprite_0_hit(&self) -> bool {
+        let y = self.oam_data[0] as usize;
+        let x = self.oam_data[3] as usize;
+        let line = self.scanlines as usize;
+        if line >= 240 || line < y || line >= y + 8 || !self.mask.show_background() || !self.mask.show_sprites() {
+            return false;
+        }
+
+        let row = render::sprite_pixels(self, 0)[line - y];
+        if row.iter().all(|&value| value == 0) {
+            return false;
+        }
+        let background = render::background_opacity_map(self);
+        let clip_left = !self.mask.show_background_left() || !self.mask.show_sprites_left();
+
+        row.iter().enumerate().any(|(col, &value)| {
+            let pixel_x = x + col;
+            value != 0 && pixel_x < 255 && !(clip_left && pixel_x < 8) && background[line * SCREEN_WIDTH + pixel_x]
+        })
+    }
+
     fn increment_vram_addr(&mut self) {
         self.addr.increment(self.control.vram_addr_increment());
     }
@@ -93,7 +193,7 @@ impl PPU {
         match addr {
             0x0000..=0x1FFF => {
                 let result = self.internal_data_buffer;
-                self.internal_data_buffer = self.chr_rom[addr as usize];
+                self.internal_data_buffer = self.ppu_chr_read(addr);
                 result
             },
             0x2000..=0x2FFF => {
@@ -102,15 +202,22 @@ impl PPU {
                 result
             },
             0x3000..=0x3EFF => panic!("Not expected to use addr space 0x3000..0x3EFF, requested = {} ", addr),
-            0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => {
-                let add_mirror = addr - 0x10;
-                self.palette[(add_mirror - 0x3f00) as usize]
-            }
-            0x3F00..=0x3FFF => self.palette[(addr - 0x3F00) as usize],
+            0x3F00..=0x3FFF => self.palette[palette_index(addr)],
             _ => panic!("Unexpected access to {}", addr),
         }
     }
 
+    // Every pattern table fetch goes through here, so CHR banking only has to be handled once.
+    // Addresses past the end of CHR (corrupt ROM, bad bank) read as 0
+    pub fn ppu_chr_read(&self, addr: u16) -> u8 {
+        self.chr_rom.get(addr as usize).copied().unwrap_or(0)
+    }
+
+    // Mappers like MMC1 can switch mirroring while the game is running
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
     pub fn mirror_vram_addr(&self, addr: u16) -> u16 {
         let mirrored_vram = addr & 0b10111111111111; // 0x2EFF, mirror down the 0x3000-0x3EFF to 0x2000-0x2EFF
         let vram_index = mirrored_vram - 0x2000; // To vram vector
@@ -125,11 +232,84 @@ impl PPU {
         }
     }
 
+    // Physical 1K VRAM page behind each of the four logical nametables, for PPU viewers
+    pub fn nametable_layout(&self) -> [u8; 4] {
+        let mut layout = [0; 4];
+        for (nametable, page) in layout.iter_mut().enumerate() {
+            let addr = 0x2000 + nametable as u16 * 0x400;
+            *page = (self.mirror_vram_addr(addr) / 0x400) as u8;
+        }
+        layout
+    }
+
+    // Write 960 tile bytes + 64 attribute bytes straight into a (mirrored) nametable
+    pub fn load_nametable(&mut self, index: u8, tiles: &[u8; 0x400]) {
+        let base = 0x2000 + (index as u16 % 4) * 0x400;
+        for (i, tile) in tiles.iter().enumerate() {
+            let addr = self.mirror_vram_addr(base + i as u16);
+            self.vram[addr as usize] = *tile;
+        }
+    }
+
+    fn is_warming_up(&self) -> bool {
+        self.warm_up && self.warm_up_cycles < WARM_UP_CYCLES
+    }
+
+    pub fn debug_state(&self) -> PpuDebugState {
+        PpuDebugState {
+            scanline: self.scanlines,
+            cycle: self.cycles,
+            vram_addr: self.addr.get(),
+            scroll_x: self.scroll.scroll_x,
+            scroll_y: self.scroll.scroll_y,
+            control: self.control.bits(),
+            mask: self.mask.bits(),
+            status: self.status.bits(),
+            oam_addr: self.oam_addr,
+            nmi_pending: self.nmi_interrupt.is_some(),
+        }
+    }
+
+    // Low 3 bits of the X scroll, the pixel offset within a tile
+    pub fn fine_x_scroll(&self) -> u8 {
+        self.scroll.scroll_x & 0b111
+    }
+
+    // Where PPUADDR currently points, for debug tools
+    pub fn current_vram_addr(&self) -> u16 {
+        self.addr.get()
+    }
+
+    // Write to a CPU mapped register (0x2000-0x2007 and its mirrors) the way the bus would
+    pub fn write_register(&mut self, reg: u16, value: u8) {
+        self.refresh_open_bus(value);
+        match reg {
+            0x2000..=0x3FFF => match reg & 0x2007 {
+                0x2000 => self.write_to_control(value),
+                0x2001 => self.write_to_mask(value),
+                0x2002 => {}, // PPUSTATUS is read only
+                0x2003 => self.write_to_oam_addr(value),
+                0x2004 => self.write_to_oam_data(value),
+                0x2005 => self.write_to_scroll(value),
+                0x2006 => self.write_to_ppu_addr(value),
+                0x2007 => self.write_to_data(value),
+                _ => panic!("not possible"),
+            },
+            _ => panic!("{:04x} is not a PPU register", reg),
+        }
+    }
+
     pub fn write_to_ppu_addr(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         self.addr.update(value);
     }
 
     pub fn write_to_control(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         let prev_nmi = self.control.generate_nmi();
         self.control.update(value);
         if !prev_nmi && self.control.generate_nmi() && self.status.in_vertical_blank() {
@@ -138,15 +318,39 @@ impl PPU {
     }
 
     pub fn write_to_mask(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         self.mask.update(value);
     }
 
     pub fn write_to_scroll(&mut self, value: u8) {
+        if self.is_warming_up() {
+            return;
+        }
         self.scroll.write(value);
     }
 
+    // Reset the PPUADDR/PPUSCROLL write toggle without the side effects of reading PPUSTATUS
+    pub fn reset_latches(&mut self) {
+        self.addr.reset_latch();
+        self.scroll.reset_latch();
+    }
+
+    // Every write to a PPU register, even a read only one, drives the latch
+    pub fn refresh_open_bus(&mut self, value: u8) {
+        self.open_bus = value;
+        self.open_bus_refreshed = self.frames;
+    }
+
+    // PPUSTATUS as a read would see it, without clearing vblank or the latches.
+    // Only the top 3 bits are driven, the rest is open bus
+    pub fn peek_status(&self) -> u8 {
+        self.status.bits() & 0b1110_0000 | self.open_bus & 0b0001_1111
+    }
+
     pub fn read_status(&mut self) -> u8 {
-        let data = self.status.bits();
+        let data = self.peek_status();
         self.status.set_vertical_blank(false);
         self.addr.reset_latch();
         self.scroll.reset_latch();
@@ -157,7 +361,16 @@ impl PPU {
         self.oam_addr = value;
     }
 
+    // Visible scanlines with either layer enabled
+    fn is_rendering(&self) -> bool {
+        self.scanlines < 240 && (self.mask.show_background() || self.mask.show_sprites())
+    }
+
     pub fn write_to_oam_data(&mut self, value: u8) {
+        if self.is_rendering() {
+            // OAM is busy with sprite evaluation, the write is dropped and OAMADDR doesn't advance
+            return;
+        }
         self.oam_data[self.oam_addr as usize] = value;
         self.oam_addr = self.oam_addr.wrapping_add(1);
     }
@@ -170,18 +383,18 @@ impl PPU {
             0x0000..=0x1FFF => panic!("Attempting to write to chr rom space {}", addr),
             0x2000..=0x2FFF => self.vram[self.mirror_vram_addr(addr) as usize] = value,
             0x3000..=0x3EFF => panic!("Not expected to use addr space 0x3000..0x3EFF, requested = {} ", addr),
-            0x3F10 | 0x3F14 | 0x3F18 | 0x3F1C => {
-                let add_mirror = addr - 0x10;
-                self.palette[(add_mirror - 0x3F00) as usize] = value;
-            }
-
-            0x3F00..=0x3FFF => self.palette[(addr - 0x3F00) as usize] = value,
+            0x3F00..=0x3FFF => self.palette[palette_index(addr)] = value,
             _ => panic!("Unexpected access to {}", addr),
         }
 
 
     }
 
+    // Replace the whole of OAM at once, leaves OAMADDR alone
+    pub fn set_oam(&mut self, oam: [u8; 256]) {
+        self.oam_data = oam;
+    }
+
     pub fn oam_dma(&mut self, data: &[u8]) {
         for x in data.iter() {
             self.oam_data[self.oam_addr as usize] = *x;
@@ -190,6 +403,16 @@ impl PPU {
     }
 }
 
+// Palette RAM repeats every 0x20 bytes up to 0x3FFF, and the sprite backdrop entries
+// 0x3F10/0x3F14/0x3F18/0x3F1C are mirrors of 0x3F00/0x3F04/0x3F08/0x3F0C
+fn palette_index(addr: u16) -> usize {
+    let index = ((addr - 0x3F00) % 0x20) as usize;
+    match index {
+        0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+        _ => index,
+    }
+}
+
 
 #[cfg(test)]
 pub mod test {
@@ -205,6 +428,50 @@ pub mod test {
         assert_eq!(ppu.vram[0x0305], 0x66);
     }
 
+    #[test]
+    fn test_open_bus_decays() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_register(0x2000, 0x1F);
+        assert_eq!(ppu.open_bus, 0x1F);
+        assert_eq!(ppu.read_status() & 0x1F, 0x1F);
+
+        for _ in 0..(ppu.open_bus_decay_frames - 1) * 262 {
+            ppu.tick(255);
+            ppu.tick(86);
+        }
+        assert_eq!(ppu.open_bus, 0x1F);
+
+        for _ in 0..262 {
+            ppu.tick(255);
+            ppu.tick(86);
+        }
+        assert_eq!(ppu.open_bus, 0);
+        assert_eq!(ppu.read_status() & 0x1F, 0);
+    }
+
+    #[test]
+    fn test_nametable_layout() {
+        let ppu = PPU::new(vec![0; 2048], Mirroring::HORIZONTAL);
+        assert_eq!(ppu.nametable_layout(), [0, 0, 1, 1]);
+
+        let ppu = PPU::new(vec![0; 2048], Mirroring::VERTICAL);
+        assert_eq!(ppu.nametable_layout(), [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_ppu_chr_read() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0x1010] = 0x42;
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        assert_eq!(ppu.ppu_chr_read(0x1010), 0x42);
+        assert_eq!(PPU::new(vec![0xFF; 0x10], Mirroring::HORIZONTAL).ppu_chr_read(0x10), 0);
+
+        ppu.write_to_ppu_addr(0x10);
+        ppu.write_to_ppu_addr(0x10);
+        ppu.read_data(); //load_into_buffer
+        assert_eq!(ppu.read_data(), 0x42);
+    }
+
     #[test]
     fn test_ppu_vram_reads() {
         let mut ppu = PPU::new_empty_rom();
@@ -310,6 +577,60 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x77); //read from B
     }
 
+    #[test]
+    fn test_set_mirroring_at_runtime() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x20);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.write_to_data(0x66); //write to A
+
+        // Horizontal: 0x2400 aliases 0x2000
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+
+        ppu.set_mirroring(Mirroring::VERTICAL);
+
+        // Vertical: 0x2800 aliases 0x2000, 0x2400 no longer does
+        ppu.write_to_ppu_addr(0x28);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x66);
+
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x05);
+        ppu.read_data(); //load into buffer
+        assert_ne!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_load_nametable() {
+        let mut ppu = PPU::new(vec![0; 2048], Mirroring::VERTICAL);
+        let mut tiles = [0; 0x400];
+        tiles[0] = 0x11;
+        tiles[0x3BF] = 0x22; // Last tile
+        tiles[0x3C0] = 0x33; // First attribute byte
+        ppu.load_nametable(1, &tiles);
+
+        ppu.write_to_ppu_addr(0x24);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x11);
+
+        ppu.write_to_ppu_addr(0x27);
+        ppu.write_to_ppu_addr(0xBF);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x22);
+        assert_eq!(ppu.read_data(), 0x33);
+
+        // Vertical mirroring: 0x2C00 shows the same table
+        ppu.write_to_ppu_addr(0x2C);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.read_data(); //load into buffer
+        assert_eq!(ppu.read_data(), 0x11);
+    }
+
     #[test]
     fn test_read_status_resets_latch() {
         let mut ppu = PPU::new_empty_rom();
@@ -356,6 +677,302 @@ pub mod test {
         assert_eq!(ppu.status.bits() >> 7, 0);
     }
 
+    // Tile 0 is solid colour 1, tile 1 is left fully transparent
+    fn solid_tile_ppu() -> PPU {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[0..8].copy_from_slice(&[0xFF; 8]);
+        PPU::new(chr_rom, Mirroring::HORIZONTAL)
+    }
+
+    fn run_to_scanline(ppu: &mut PPU, scanline: u16) {
+        while ppu.scanlines < scanline {
+            ppu.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_sprite_0_hit() {
+        let mut ppu = solid_tile_ppu();
+        ppu.oam_data[0] = 5; // y
+        ppu.oam_data[3] = 10; // x
+        ppu.write_to_mask(0b0001_1000); // Show background and sprites
+
+        run_to_scanline(&mut ppu, 6);
+        assert!(ppu.status.sprite_0_hit());
+    }
+
+    #[test]
+    fn test_sprite_0_hit_needs_opaque_sprite() {
+        let mut ppu = solid_tile_ppu();
+        ppu.oam_data[0] = 5;
+        ppu.oam_data[1] = 1; // Transparent tile over an opaque background
+        ppu.oam_data[3] = 10;
+        ppu.write_to_mask(0b0001_1000);
+
+        run_to_scanline(&mut ppu, 20);
+        assert!(!ppu.status.sprite_0_hit());
+    }
+
+    #[test]
+    fn test_sprite_0_hit_needs_opaque_background() {
+        let mut ppu = solid_tile_ppu();
+        ppu.write_to_control(0b0001_0000); // Background from the transparent 0x1000 table
+        ppu.oam_data[0] = 5;
+        ppu.oam_data[3] = 10;
+        ppu.write_to_mask(0b0001_1000);
+
+        run_to_scanline(&mut ppu, 20);
+        assert!(!ppu.status.sprite_0_hit());
+    }
+
+    #[test]
+    fn test_sprite_0_hit_edges() {
+        // Only the sprite's first column is opaque
+        let mut ppu = solid_tile_ppu();
+        ppu.chr_rom[16..24].copy_from_slice(&[0x80; 8]);
+        ppu.oam_data[0] = 5;
+        ppu.oam_data[1] = 1;
+        ppu.oam_data[3] = 255;
+        ppu.write_to_mask(0b0001_1110); // Nothing clipped
+        run_to_scanline(&mut ppu, 20);
+        assert!(!ppu.status.sprite_0_hit()); // Never at x 255
+
+        ppu.scanlines = 0;
+        ppu.oam_data[3] = 4;
+        ppu.write_to_mask(0b0001_1010); // Sprites clipped on the left edge
+        run_to_scanline(&mut ppu, 20);
+        assert!(!ppu.status.sprite_0_hit());
+
+        ppu.scanlines = 0;
+        ppu.write_to_mask(0b0001_1110);
+        run_to_scanline(&mut ppu, 20);
+        assert!(ppu.status.sprite_0_hit());
+    }
+
+    #[test]
+    fn test_sprite_0_hit_not_in_vblank() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.oam_data[0] = 245; // y, below the visible area
+        ppu.write_to_mask(0b0001_1000);
+
+        while ppu.scanlines < 247 {
+            ppu.tick(1);
+        }
+        assert!(!ppu.status.sprite_0_hit());
+    }
+
+    #[test]
+    fn test_sprite_0_hit_needs_background() {
+        let mut ppu = solid_tile_ppu();
+        ppu.oam_data[0] = 5; // y
+        ppu.oam_data[3] = 10; // x
+        ppu.write_to_mask(0b0001_0000); // Show sprites only
+
+        while ppu.scanlines < 6 {
+            ppu.tick(1);
+        }
+        assert!(!ppu.status.sprite_0_hit());
+    }
+
+    #[test]
+    fn test_cycles_until_next_event() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.scanlines = 100;
+        ppu.cycles = 200;
+        assert_eq!(ppu.cycles_until_next_event(), 141);
+
+        ppu.tick(140);
+        assert_eq!(ppu.scanlines, 100);
+        ppu.tick(1);
+        assert_eq!(ppu.scanlines, 101);
+        assert_eq!(ppu.cycles, 0);
+
+        // Landing on the end of scanline 240 raises vblank
+        ppu.scanlines = 240;
+        ppu.cycles = 100;
+        let skip = ppu.cycles_until_next_event();
+        assert_eq!(skip, 241);
+        ppu.tick(skip);
+        assert!(ppu.status.in_vertical_blank());
+    }
+
+    #[test]
+    fn test_cycles_until_next_event_from_scanline_start() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.scanlines = 10;
+        assert_eq!(ppu.cycles_until_next_event(), 255); // 341 doesn't fit in a tick
+
+        ppu.tick(ppu.cycles_until_next_event());
+        assert_eq!(ppu.scanlines, 10);
+        assert_eq!(ppu.cycles_until_next_event(), 86);
+
+        ppu.tick(ppu.cycles_until_next_event());
+        assert_eq!(ppu.scanlines, 11);
+        assert_eq!(ppu.cycles, 0);
+    }
+
+    #[test]
+    fn test_warm_up_ignores_control_writes() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.warm_up = true;
+        ppu.write_to_control(0x80);
+        assert!(!ppu.control.generate_nmi());
+
+        for _ in 0..WARM_UP_CYCLES / 255 + 1 {
+            ppu.tick(255);
+        }
+        ppu.write_to_control(0x80);
+        assert!(ppu.control.generate_nmi());
+    }
+
+    #[test]
+    fn test_warm_up_off_by_default() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_control(0x80);
+        assert!(ppu.control.generate_nmi());
+    }
+
+    #[test]
+    fn test_sprite_0_bounds() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.oam_data[0] = 5; // y
+        ppu.oam_data[3] = 10; // x
+        assert_eq!(ppu.sprite_0_bounds(), (10, 5, 8, 8));
+
+        ppu.write_to_control(0b0010_0000); // 8x16 sprites
+        assert_eq!(ppu.sprite_0_bounds(), (10, 5, 8, 16));
+    }
+
+    #[test]
+    fn test_debug_state() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_control(0x80);
+        ppu.write_to_oam_addr(0x10);
+        for _ in 0..3 {
+            ppu.tick(200);
+        }
+
+        let state = ppu.debug_state();
+        assert_eq!(state.scanline, 1);
+        assert_eq!(state.cycle, 600 - 341);
+        assert_eq!(state.control, 0x80);
+        assert_eq!(state.oam_addr, 0x10);
+        assert!(!state.nmi_pending);
+    }
+
+    #[test]
+    fn test_palette_mirrors_above_3f20() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x00);
+        ppu.write_to_data(0x21);
+
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0x20);
+        assert_eq!(ppu.read_data(), 0x21);
+
+        // Backdrop mirror of a mirror
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0xF0);
+        ppu.write_to_data(0x16);
+        assert_eq!(ppu.palette[0], 0x16);
+
+        ppu.write_to_ppu_addr(0x3F);
+        ppu.write_to_ppu_addr(0xE5);
+        ppu.write_to_data(0x2A);
+        assert_eq!(ppu.palette[0x05], 0x2A);
+    }
+
+    #[test]
+    fn test_write_register() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_register(0x2000, 0x80);
+        assert!(ppu.control.generate_nmi());
+
+        // Mirrored every 8 bytes
+        ppu.write_register(0x3FFE, 0x23);
+        ppu.write_register(0x2006, 0x05);
+        assert_eq!(ppu.current_vram_addr(), 0x2305);
+    }
+
+    #[test]
+    fn test_backdrop_mirrors_both_ways() {
+        let mut ppu = PPU::new_empty_rom();
+        for mirror in [0x10, 0x14, 0x18, 0x1C] {
+            let base = mirror - 0x10;
+            ppu.write_to_ppu_addr(0x3F);
+            ppu.write_to_ppu_addr(base);
+            ppu.write_to_data(0x0D);
+
+            ppu.write_to_ppu_addr(0x3F);
+            ppu.write_to_ppu_addr(mirror);
+            assert_eq!(ppu.read_data(), 0x0D);
+
+            ppu.write_to_ppu_addr(0x3F);
+            ppu.write_to_ppu_addr(mirror);
+            ppu.write_to_data(0x1E);
+
+            ppu.write_to_ppu_addr(0x3F);
+            ppu.write_to_ppu_addr(base);
+            assert_eq!(ppu.read_data(), 0x1E);
+        }
+    }
+
+    #[test]
+    fn test_reset_latches() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x21);
+        ppu.reset_latches();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.current_vram_addr(), 0x2305);
+
+        ppu.write_to_scroll(0x10);
+        ppu.reset_latches();
+        ppu.write_to_scroll(0x20);
+        assert_eq!(ppu.scroll.scroll_x, 0x20);
+        assert_eq!(ppu.scroll.scroll_y, 0);
+    }
+
+    #[test]
+    fn test_fine_x_scroll() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_scroll(0b0101_1101); // Coarse X 11, fine X 5
+        ppu.write_to_scroll(0x07); // Y doesn't affect it
+        assert_eq!(ppu.fine_x_scroll(), 5);
+    }
+
+    #[test]
+    fn test_current_vram_addr() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_ppu_addr(0x23);
+        ppu.write_to_ppu_addr(0x05);
+        assert_eq!(ppu.current_vram_addr(), 0x2305);
+
+        // Only 14 bits are kept
+        ppu.write_to_ppu_addr(0x7F);
+        ppu.write_to_ppu_addr(0xFF);
+        assert_eq!(ppu.current_vram_addr(), 0x3FFF);
+    }
+
+    #[test]
+    fn test_new_with_oam_pattern() {
+        let mut ppu = PPU::new_with_oam_pattern(vec![0; 2048], Mirroring::HORIZONTAL, 0xFF);
+        assert_eq!(ppu.read_oam_data(), 0xFF);
+        ppu.write_to_oam_addr(0xAB);
+        assert_eq!(ppu.read_oam_data(), 0xFF);
+    }
+
+    #[test]
+    fn test_sprite_scanline_range() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.oam_data[3 * 4] = 50; // Sprite 3 y
+        assert_eq!(ppu.sprite_scanline_range(3), (50, 57));
+
+        ppu.write_to_control(0b0010_0000); // 8x16 sprites
+        assert_eq!(ppu.sprite_scanline_range(3), (50, 65));
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = PPU::new_empty_rom();
@@ -370,6 +987,23 @@ pub mod test {
         assert_eq!(ppu.read_oam_data(), 0x77);
     }
 
+    #[test]
+    fn test_oam_write_during_rendering() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_to_mask(0b0001_1000);
+        ppu.scanlines = 100;
+
+        ppu.write_to_oam_addr(0x10);
+        ppu.write_to_oam_data(0x66);
+        assert_eq!(ppu.oam_addr, 0x10);
+        assert_eq!(ppu.oam_data[0x10], 0x00);
+
+        ppu.scanlines = 241; // vblank
+        ppu.write_to_oam_data(0x66);
+        assert_eq!(ppu.oam_addr, 0x11);
+        assert_eq!(ppu.oam_data[0x10], 0x66);
+    }
+
     #[test]
     fn test_oam_dma() {
         let mut ppu = PPU::new_empty_rom();
@@ -54,3 +54,30 @@ impl AddrRegister {
     }
 }
 
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_update_masks_to_14_bits() {
+        let mut addr = AddrRegister::new();
+        addr.update(0x7F);
+        addr.update(0xFF);
+        assert_eq!(addr.get(), 0x3FFF);
+    }
+
+    #[test]
+    fn test_increment_wraps_at_14_bits() {
+        let mut addr = AddrRegister::new();
+        addr.update(0x3F);
+        addr.update(0xFF);
+        addr.increment(1);
+        assert_eq!(addr.get(), 0x0000);
+
+        addr.update(0x3F);
+        addr.update(0xF0);
+        addr.increment(32);
+        assert_eq!(addr.get(), 0x0010);
+    }
+}
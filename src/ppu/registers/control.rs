@@ -29,6 +29,16 @@ impl ControlRegister {
         ControlRegister::from_bits_truncate(0b00000000)
     }
 
+    pub fn nametable_addr(&self) -> u16 {
+        match self.bits & 0b11 {
+            0 => 0x2000,
+            1 => 0x2400,
+            2 => 0x2800,
+            3 => 0x2C00,
+            _ => panic!("not possible"),
+        }
+    }
+
     pub fn background_pattern_addr(&self) -> u16 {
         if self.contains(ControlRegister::BACKROUND_PATTERN_ADDR) {
             0x1000
@@ -53,6 +63,14 @@ impl ControlRegister {
         }
     }
 
+    pub fn sprite_size(&self) -> u8 {
+        if !self.contains(ControlRegister::SPRITE_SIZE) {
+            8
+        } else {
+            16
+        }
+    }
+
     pub fn generate_nmi(&self) -> bool {
         self.contains(ControlRegister::GENERATE_NMI)
     }
@@ -44,6 +44,14 @@ impl MaskRegister {
         self.contains(MaskRegister::SHOW_BACKGROUND)
     }
 
+    pub fn show_sprites_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_SPRITES_LEFT)
+    }
+
+    pub fn show_background_left(&self) -> bool {
+        self.contains(MaskRegister::SHOW_BACKGROUND_LEFT)
+    }
+
     pub fn is_greyscale(&self) -> bool {
         self.contains(MaskRegister::GREYSCALE)
     }
@@ -1,10 +1,12 @@
+use super::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
 pub struct Frame {
     pub data: Vec<u8>,
 }
 
 impl Frame {
-    const WIDTH: usize = 256;
-    const HEIGHT: usize = 240;
+    const WIDTH: usize = SCREEN_WIDTH;
+    const HEIGHT: usize = SCREEN_HEIGHT;
 
     pub fn new() -> Self {
         Frame {
@@ -20,4 +22,50 @@ impl Frame {
             self.data[base+2] = rgb.2;
         }
     }
+
+    // Nearest-neighbour upscale, returns the RGB buffer along with its width and height
+    pub fn scaled(&self, factor: usize) -> (Vec<u8>, usize, usize) {
+        let width = Frame::WIDTH * factor;
+        let height = Frame::HEIGHT * factor;
+        let mut data = vec![0; width * height * 3];
+
+        for y in 0..height {
+            for x in 0..width {
+                let src = (y / factor) * 3 * Frame::WIDTH + (x / factor) * 3;
+                let dst = y * 3 * width + x * 3;
+                data[dst..dst + 3].copy_from_slice(&self.data[src..src + 3]);
+            }
+        }
+        (data, width, height)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scaled() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, (1, 2, 3));
+        frame.set_pixel(1, 0, (4, 5, 6));
+        frame.set_pixel(0, 1, (7, 8, 9));
+        frame.set_pixel(1, 1, (10, 11, 12));
+
+        let (data, width, height) = frame.scaled(2);
+        assert_eq!((width, height), (512, 480));
+        assert_eq!(data.len(), 512 * 480 * 3);
+
+        let pixel = |x: usize, y: usize| {
+            let base = y * 3 * width + x * 3;
+            (data[base], data[base + 1], data[base + 2])
+        };
+        for (x, y, rgb) in [(0, 0, (1, 2, 3)), (1, 0, (4, 5, 6)), (0, 1, (7, 8, 9)), (1, 1, (10, 11, 12))] {
+            assert_eq!(pixel(x * 2, y * 2), rgb);
+            assert_eq!(pixel(x * 2 + 1, y * 2), rgb);
+            assert_eq!(pixel(x * 2, y * 2 + 1), rgb);
+            assert_eq!(pixel(x * 2 + 1, y * 2 + 1), rgb);
+        }
+    }
 }
\ No newline at end of file
@@ -4,88 +4,265 @@ pub mod palette;
 use crate::ppu::ppu::PPU;
 use frame::Frame;
 
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+// Off draws every sprite. Rotate applies the hardware limit of 8 sprites per scanline and
+// shifts the OAM index evaluation starts from each frame, so different sprites drop out
+// every frame the way games flicker them
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FlickerMode {
+    Off,
+    Rotate,
+}
+
+const SPRITES_PER_SCANLINE: u8 = 8;
+
 pub fn render(ppu: &PPU, frame: &mut Frame) {
+    render_with_flicker(ppu, frame, FlickerMode::Off);
+}
+
+pub fn render_with_flicker(ppu: &PPU, frame: &mut Frame, flicker: FlickerMode) {
     let bank = ppu.control.background_pattern_addr();
+    let backdrop = backdrop_color(ppu, &palette::SYSTEM_PALLETE);
 
     for i in 0..0x03C0 {
         // just for now, lets use the first nametable
         let tile = ppu.vram[i] as u16;
         let tile_x = i % 32;
         let tile_y = i / 32;
-        let tile = &ppu.chr_rom[(bank + tile * 16) as usize..=(bank + tile * 16 + 15) as usize];
-        let palette = bg_pallette(ppu, tile_x, tile_y);
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-
-            for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[palette[0] as usize],
-                    1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-                    _ => panic!("Palette selection out of bounds for background"),
-                };
+        let tile = chr_tile(ppu, bank, tile);
+        let palette = bg_pallette(ppu, 0x2000, tile_x, tile_y);
+
+        for (y, row) in decode_tile(&tile).iter().enumerate() {
+            for (x, &value) in row.iter().enumerate() {
+                let rgb = pixel_color(&palette, value, &palette::SYSTEM_PALLETE).unwrap_or(backdrop);
                 frame.set_pixel(tile_x * 8 + x, tile_y * 8 + y, rgb)
             }
         }
     }
 
-    for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-        let tile_i = ppu.oam_data[i + 1] as u16;
+    // Sprites in evaluation order, earlier sprites take priority
+    let start = match flicker {
+        FlickerMode::Off => 0,
+        FlickerMode::Rotate => ppu.frames % 64,
+    };
+    let order: Vec<usize> = (0..64).map(|n| (start + n) % 64).collect();
+
+    // Which of each sprite's 8 rows survive the per-scanline limit
+    let mut visible_rows = [[true; 8]; 64];
+    if flicker == FlickerMode::Rotate {
+        let mut scanline_sprites = [0u8; 256];
+        for &sprite in order.iter() {
+            let tile_y = ppu.oam_data[sprite * 4] as usize;
+            for row in 0..8 {
+                if tile_y + row >= scanline_sprites.len() {
+                    break;
+                }
+                if scanline_sprites[tile_y + row] < SPRITES_PER_SCANLINE {
+                    scanline_sprites[tile_y + row] += 1;
+                } else {
+                    visible_rows[sprite][row] = false;
+                }
+            }
+        }
+    }
+
+    // Highest priority first, a pixel belongs to the first sprite that draws it opaquely.
+    // Transparent pixels leave it free for sprites further down. A sprite behind the background
+    // still claims its pixel, it just isn't drawn over opaque background
+    let background = background_opacity_map(ppu);
+    let mut sprite_drawn = vec![false; SCREEN_WIDTH * SCREEN_HEIGHT];
+    for &sprite in order.iter() {
+        let i = sprite * 4;
         let tile_x = ppu.oam_data[i + 3] as usize;
         let tile_y = ppu.oam_data[i] as usize;
 
-        let flip_vertical = ppu.oam_data[i + 2] >> 7 & 1 == 1;
-        let flip_horizontal = ppu.oam_data[i + 2] >> 6 & 1 == 1;
         let pallette_i = ppu.oam_data[i + 2] & 0b11;
         let sprite_palette = sprite_palette(ppu, pallette_i);
+        let behind_background = ppu.oam_data[i + 2] >> 5 & 1 == 1;
+
+        for (y, row) in sprite_pixels(ppu, sprite).iter().enumerate() {
+            if !visible_rows[sprite][y] {
+                continue;
+            }
 
-        let bank: u16 = ppu.control.sprite_pattern_addr();
-
-        let tile =
-            &ppu.chr_rom[(bank + tile_i * 16) as usize..=(bank + tile_i * 16 + 15) as usize];
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-            for x in (0..=7).rev() {
-                let value = (1 & lower) << 1 | (1 & upper);
-                upper = upper >> 1;
-                lower = lower >> 1;
-                let rgb = match value {
-                    0 => continue, // skip coloring the pixel
-                    1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-                    2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-                    3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-                    _ => panic!("Palette selection out of bounds for sprite"),
+            for (x, &value) in row.iter().enumerate() {
+                let rgb = match pixel_color(&sprite_palette, value, &palette::SYSTEM_PALLETE) {
+                    Some(rgb) => rgb,
+                    None => continue, // skip coloring the pixel
                 };
-                match (flip_horizontal, flip_vertical) {
-                    (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-                    (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-                    (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-                    (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
+                let pixel_x = tile_x + x;
+                let pixel_y = tile_y + y;
+                if pixel_x >= SCREEN_WIDTH || pixel_y >= SCREEN_HEIGHT {
+                    continue;
                 }
+
+                let pixel_i = pixel_y * SCREEN_WIDTH + pixel_x;
+                if !sprite_drawn[pixel_i] {
+                    sprite_drawn[pixel_i] = true;
+                    if !(behind_background && background[pixel_i]) {
+                        frame.set_pixel(pixel_x, pixel_y, rgb);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// 2 bit colour values of an OAM sprite's pixels as they land on screen, indexed [y][x]
+// from its top left corner with the attribute flips already applied
+pub fn sprite_pixels(ppu: &PPU, oam_index: usize) -> [[u8; 8]; 8] {
+    let i = oam_index * 4;
+    let tile = chr_tile(ppu, ppu.control.sprite_pattern_addr(), ppu.oam_data[i + 1] as u16);
+    let mut pixels = decode_tile(&tile);
+
+    if ppu.oam_data[i + 2] >> 6 & 1 == 1 {
+        for row in pixels.iter_mut() {
+            row.reverse();
+        }
+    }
+    if ppu.oam_data[i + 2] >> 7 & 1 == 1 {
+        pixels.reverse();
+    }
+    pixels
+}
+
+// SCREEN_WIDTH x SCREEN_HEIGHT map of the pixels the background draws opaquely
+// (non zero colour), indexed y * SCREEN_WIDTH + x
+pub fn background_opacity_map(ppu: &PPU) -> Vec<bool> {
+    let mut map = vec![false; SCREEN_WIDTH * SCREEN_HEIGHT];
+    let bank = ppu.control.background_pattern_addr();
+
+    for i in 0..0x03C0 {
+        let tile = ppu.vram[i] as u16;
+        let tile_x = i % 32;
+        let tile_y = i / 32;
+        let tile = chr_tile(ppu, bank, tile);
+
+        for (y, row) in decode_tile(&tile).iter().enumerate() {
+            for (x, &value) in row.iter().enumerate() {
+                map[(tile_y * 8 + y) * SCREEN_WIDTH + tile_x * 8 + x] = value != 0;
             }
         }
     }
+    map
+}
+
+// Nametable entry and pattern row address behind a screen pixel, after applying scroll
+// and the base nametable from PPUCTRL. Scrolling past the edge moves into the next nametable
+pub fn tile_addr_for_pixel(ppu: &PPU, screen_x: usize, screen_y: usize) -> (u16, u16) {
+    let mut x = screen_x + ppu.scroll.scroll_x as usize;
+    let mut y = screen_y + ppu.scroll.scroll_y as usize;
+    let mut nametable = (ppu.control.nametable_addr() - 0x2000) / 0x400;
+    if x >= SCREEN_WIDTH {
+        x -= SCREEN_WIDTH;
+        nametable ^= 0b01;
+    }
+    if y >= SCREEN_HEIGHT {
+        y -= SCREEN_HEIGHT;
+        nametable ^= 0b10;
+    }
+
+    let nametable_addr = 0x2000 + nametable * 0x400 + (y / 8 * 32 + x / 8) as u16;
+    let tile = ppu.vram[ppu.mirror_vram_addr(nametable_addr) as usize] as u16;
+    let pattern_addr = ppu.control.background_pattern_addr() + tile * 16 + (y % 8) as u16;
+    (nametable_addr, pattern_addr)
+}
+
+// Overlays lines on the 8x8 tile boundaries of an already rendered frame, handy when
+// checking scroll offsets
+pub fn draw_tile_grid(frame: &mut Frame, color: (u8, u8, u8)) {
+    for y in 0..SCREEN_HEIGHT {
+        for x in 0..SCREEN_WIDTH {
+            if x % 8 == 0 || y % 8 == 0 {
+                frame.set_pixel(x, y, color);
+            }
+        }
+    }
+}
+
+// Tints each 16x16 area of the first nametable by the palette its attribute bits select,
+// blending half and half with what's already in the frame
+pub fn draw_attribute_overlay(ppu: &PPU, frame: &mut Frame) {
+    let tints: [(u8, u8, u8); 4] = [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 0)];
+
+    for y in 0..SCREEN_HEIGHT {
+        for x in 0..SCREEN_WIDTH {
+            let tint = tints[attribute_for_tile(ppu, 0x2000, x / 8, y / 8) as usize];
+            let base = y * 3 * SCREEN_WIDTH + x * 3;
+            let blend = |old: u8, tint: u8| ((old as u16 + tint as u16) / 2) as u8;
+            let rgb = (
+                blend(frame.data[base], tint.0),
+                blend(frame.data[base + 1], tint.1),
+                blend(frame.data[base + 2], tint.2),
+            );
+            frame.set_pixel(x, y, rgb);
+        }
+    }
+}
+
+// Colour of a 2 bit pixel value within a 4 colour palette, None when it's transparent (0)
+fn pixel_color(palette: &[u8; 4], value: u8, palette_table: &palette::Palette) -> Option<(u8, u8, u8)> {
+    match value {
+        0 => None,
+        1..=3 => Some(palette_table[palette[value as usize] as usize]),
+        _ => panic!("Palette selection out of bounds"),
+    }
 }
 
-pub fn bg_pallette(ppu: &PPU, tile_column: usize, tile_row: usize) -> [u8; 4] {
+// The universal backdrop colour at $3F00, shown wherever the background is transparent.
+// Looked up through pixel_color like every other pixel so colour handling stays in one place
+fn backdrop_color(ppu: &PPU, palette_table: &palette::Palette) -> (u8, u8, u8) {
+    pixel_color(&[ppu.palette[0]; 4], 1, palette_table).expect("index 1 is opaque")
+}
+
+// Fetch the 16 bytes of a tile through the PPU so the active CHR bank is respected
+// Tiles past the end of CHR (corrupt ROM, bad bank) come back blank
+fn chr_tile(ppu: &PPU, bank: u16, tile_i: u16) -> [u8; 16] {
+    let mut tile = [0; 16];
+    for (i, byte) in tile.iter_mut().enumerate() {
+        *byte = ppu.ppu_chr_read(bank + tile_i * 16 + i as u16);
+    }
+    tile
+}
+
+// 2 bit colour values of a tile's 8x8 pixels, indexed [y][x]. Each row combines a low
+// plane byte (0-7) with the high plane byte 8 further on, leftmost pixel in the top bit
+fn decode_tile(tile: &[u8; 16]) -> [[u8; 8]; 8] {
+    let mut pixels = [[0; 8]; 8];
+    for (y, row) in pixels.iter_mut().enumerate() {
+        let mut upper = tile[y];
+        let mut lower = tile[y + 8];
+
+        for x in (0..=7).rev() {
+            row[x] = (1 & lower) << 1 | (1 & upper);
+            upper >>= 1;
+            lower >>= 1;
+        }
+    }
+    pixels
+}
+
+// Palette selection (0-3) the attribute table gives a tile, each attribute byte covers a 4x4 tile area.
+// nametable_base is the PPU address of the nametable (0x2000, 0x2400, 0x2800 or 0x2C00),
+// its attribute table sits in the last 64 bytes
+pub fn attribute_for_tile(ppu: &PPU, nametable_base: u16, tile_column: usize, tile_row: usize) -> u8 {
     let attr_table_i = tile_row / 4 * 8 + tile_column / 4;
-    let attr_byte = ppu.vram[0x3c0 + attr_table_i];
+    let addr = nametable_base + 0x3c0 + attr_table_i as u16;
+    let attr_byte = ppu.vram[ppu.mirror_vram_addr(addr) as usize];
 
-    let pallet_i = match (tile_column % 4 / 2, tile_row % 4 / 2) {
+    match (tile_column % 4 / 2, tile_row % 4 / 2) {
         (0, 0) => attr_byte & 0b11,
         (1, 0) => (attr_byte >> 2) & 0b11,
         (0, 1) => (attr_byte >> 4) & 0b11,
         (1, 1) => (attr_byte >> 6) & 0b11,
         (_, _) => panic!("should not happen"),
-    };
+    }
+}
+
+pub fn bg_pallette(ppu: &PPU, nametable_base: u16, tile_column: usize, tile_row: usize) -> [u8; 4] {
+    let pallet_i = attribute_for_tile(ppu, nametable_base, tile_column, tile_row);
 
     let start: usize = 1 + (pallet_i as usize) * 4;
     // Default
@@ -97,6 +274,11 @@ pub fn bg_pallette(ppu: &PPU, tile_column: usize, tile_row: usize) -> [u8; 4] {
     ]
 }
 
+// RGB of the four colours a first nametable background tile is drawn with
+pub fn tile_palette_colors(ppu: &PPU, tile_column: usize, tile_row: usize, palette: &palette::Palette) -> [(u8, u8, u8); 4] {
+    bg_pallette(ppu, 0x2000, tile_column, tile_row).map(|colour| palette[colour as usize])
+}
+
 pub fn sprite_palette(ppu: &PPU, palette_i: u8) -> [u8; 4] {
     let start = 0x11 + (palette_i * 4) as usize;
     [
@@ -105,4 +287,323 @@ pub fn sprite_palette(ppu: &PPU, palette_i: u8) -> [u8; 4] {
         ppu.palette[start + 1],
         ppu.palette[start + 2],
     ]
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::Mirroring;
+
+    fn pixel(frame: &Frame, x: usize, y: usize) -> (u8, u8, u8) {
+        let base = y * 3 * SCREEN_WIDTH + x * 3;
+        (frame.data[base], frame.data[base + 1], frame.data[base + 2])
+    }
+
+    #[test]
+    fn test_frame_size() {
+        let ppu = PPU::new_empty_rom();
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(frame.data.len(), SCREEN_WIDTH * SCREEN_HEIGHT * 3);
+    }
+
+    #[test]
+    fn test_render_reads_tiles_through_chr() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16] = 0xFF; // Tile 1, row 0, low plane
+
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.vram[0] = 1;
+        ppu.palette[0] = 0x0F;
+        ppu.palette[1] = 0x30;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x30]);
+
+        // Swapping the pattern data underneath changes what gets drawn
+        ppu.chr_rom[16] = 0x00;
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 0), palette::SYSTEM_PALLETE[0x0F]);
+    }
+
+    #[test]
+    fn test_flicker_rotates_dropped_sprites() {
+        let mut chr_rom = vec![0; 0x2000];
+        for row in 0..8 {
+            chr_rom[16 + row] = 0xFF; // Tile 1 is solid colour 1
+        }
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette[0] = 0x0F;
+        ppu.palette[0x11] = 0x30;
+
+        // 9 sprites on the same scanlines, one too many
+        for sprite in 0..9 {
+            ppu.oam_data[sprite * 4] = 10;
+            ppu.oam_data[sprite * 4 + 1] = 1;
+            ppu.oam_data[sprite * 4 + 3] = (sprite * 10) as u8;
+        }
+        // Park the rest off the bottom of the screen
+        for sprite in 9..64 {
+            ppu.oam_data[sprite * 4] = 0xF0;
+        }
+
+        let sprite_colour = palette::SYSTEM_PALLETE[0x30];
+        let backdrop = palette::SYSTEM_PALLETE[0x0F];
+        let mut frame = Frame::new();
+
+        render_with_flicker(&ppu, &mut frame, FlickerMode::Off);
+        assert_eq!(pixel(&frame, 0, 10), sprite_colour);
+        assert_eq!(pixel(&frame, 80, 10), sprite_colour);
+
+        ppu.frames = 0;
+        render_with_flicker(&ppu, &mut frame, FlickerMode::Rotate);
+        assert_eq!(pixel(&frame, 0, 10), sprite_colour);
+        assert_eq!(pixel(&frame, 80, 10), backdrop); // Sprite 8 dropped
+
+        ppu.frames = 1;
+        render_with_flicker(&ppu, &mut frame, FlickerMode::Rotate);
+        assert_eq!(pixel(&frame, 0, 10), backdrop); // Sprite 0 dropped
+        assert_eq!(pixel(&frame, 80, 10), sprite_colour);
+    }
+
+    #[test]
+    fn test_transparent_background_uses_universal_backdrop() {
+        let mut ppu = PPU::new(vec![0; 0x2000], Mirroring::HORIZONTAL); // Every pixel is colour 0
+        ppu.palette[0] = 0x0F;
+        ppu.palette[0x04] = 0x11;
+        ppu.palette[0x08] = 0x22;
+        ppu.palette[0x0C] = 0x33;
+        ppu.vram[0x3C0] = 0b11_10_01_00; // Top left 4x4 tiles use all four palettes
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        let backdrop = palette::SYSTEM_PALLETE[0x0F];
+        assert_eq!(pixel(&frame, 0, 0), backdrop);
+        assert_eq!(pixel(&frame, 16, 0), backdrop);
+        assert_eq!(pixel(&frame, 0, 16), backdrop);
+        assert_eq!(pixel(&frame, 16, 16), backdrop);
+    }
+
+    #[test]
+    fn test_pixel_color() {
+        let table = palette::SYSTEM_PALLETE;
+        let colours = [0x0F, 0x01, 0x02, 0x03];
+        assert_eq!(pixel_color(&colours, 0, &table), None);
+        assert_eq!(pixel_color(&colours, 1, &table), Some(table[0x01]));
+        assert_eq!(pixel_color(&colours, 2, &table), Some(table[0x02]));
+        assert_eq!(pixel_color(&colours, 3, &table), Some(table[0x03]));
+
+        // Index 0 is transparent whatever the palette holds there
+        let opaque_backdrop = [0x30, 0x30, 0x30, 0x30];
+        assert_eq!(pixel_color(&opaque_backdrop, 0, &table), None);
+        assert_eq!(pixel_color(&opaque_backdrop, 3, &table), Some(table[0x30]));
+    }
+
+    #[test]
+    fn test_backdrop_color() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.palette[0] = 0x21;
+        let table = palette::SYSTEM_PALLETE;
+        assert_eq!(backdrop_color(&ppu, &table), table[0x21]);
+    }
+
+    #[test]
+    fn test_background_opacity_map() {
+        let mut chr_rom = vec![0; 0x2000];
+        for row in 0..8 {
+            chr_rom[16 + row] = 0xFF; // Tile 1 is solid colour 1
+        }
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        for i in 0..0x1E0 {
+            ppu.vram[i] = 1; // Top 15 rows of tiles
+        }
+
+        let map = background_opacity_map(&ppu);
+        assert_eq!(map.len(), SCREEN_WIDTH * SCREEN_HEIGHT);
+        assert!(map[0]);
+        assert!(map[119 * 256 + 255]);
+        assert!(!map[120 * 256]);
+        assert!(!map[239 * 256 + 255]);
+        assert_eq!(map.iter().filter(|opaque| **opaque).count(), 256 * 120);
+    }
+
+    #[test]
+    fn test_tile_addr_for_pixel() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.vram[0] = 3;
+        assert_eq!(tile_addr_for_pixel(&ppu, 0, 0), (0x2000, 3 * 16));
+        assert_eq!(tile_addr_for_pixel(&ppu, 9, 2), (0x2001, 2));
+
+        // Scrolled 8 pixels right, the last column comes from the next nametable
+        ppu.write_to_scroll(8);
+        ppu.write_to_scroll(0);
+        assert_eq!(tile_addr_for_pixel(&ppu, 250, 0).0, 0x2400);
+
+        // Base nametable 3, scrolling down wraps back vertically to nametable 1
+        ppu.write_to_control(0b11);
+        ppu.write_to_scroll(0);
+        ppu.write_to_scroll(16);
+        assert_eq!(tile_addr_for_pixel(&ppu, 0, 232).0, 0x2400 + 32);
+    }
+
+    #[test]
+    fn test_tile_outside_chr_is_blank() {
+        let mut ppu = PPU::new(vec![0xFF; 0x20], Mirroring::HORIZONTAL); // Only tiles 0 and 1
+        ppu.vram[32] = 1; // Second row, clear of the sprites sitting at 0,0
+        ppu.vram[33] = 2;
+        ppu.palette[0] = 0x0F;
+        ppu.palette[3] = 0x30;
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 0, 8), palette::SYSTEM_PALLETE[0x30]);
+        assert_eq!(pixel(&frame, 8, 8), palette::SYSTEM_PALLETE[0x0F]);
+    }
+
+    #[test]
+    fn test_tile_palette_colors() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.vram[0x3C0] = 0b0000_1000; // Top right 2x2 tiles use palette 2
+        ppu.palette[0] = 0x0F;
+        ppu.palette[9..12].copy_from_slice(&[0x16, 0x27, 0x30]);
+
+        let table = palette::SYSTEM_PALLETE;
+        assert_eq!(tile_palette_colors(&ppu, 2, 0, &table), [table[0x0F], table[0x16], table[0x27], table[0x30]]);
+        assert_eq!(tile_palette_colors(&ppu, 0, 0, &table)[0], table[0x0F]);
+    }
+
+    #[test]
+    fn test_overlapping_sprite_priority() {
+        let mut chr_rom = vec![0; 0x2000];
+        for row in 0..8 {
+            chr_rom[16 + row] = 0xF0; // Tile 1, left half opaque
+            chr_rom[32 + row] = 0xFF; // Tile 2, solid
+        }
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette[0] = 0x0F;
+        ppu.palette[0x11] = 0x16; // Sprite palette 0
+        ppu.palette[0x15] = 0x2A; // Sprite palette 1
+
+        let mut oam = [0xF0; 256];
+        oam[0..4].copy_from_slice(&[10, 1, 0, 10]); // Sprite 0, tile 1, palette 0
+        oam[4..8].copy_from_slice(&[10, 2, 1, 10]); // Sprite 1, tile 2, palette 1
+        ppu.set_oam(oam);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        // Sprite 0 wins where it's opaque, sprite 1 shows through its transparent half
+        assert_eq!(pixel(&frame, 10, 10), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 13, 17), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 14, 10), palette::SYSTEM_PALLETE[0x2A]);
+        assert_eq!(pixel(&frame, 17, 17), palette::SYSTEM_PALLETE[0x2A]);
+    }
+
+    #[test]
+    fn test_sprite_behind_background() {
+        let mut chr_rom = vec![0; 0x2000];
+        for row in 0..8 {
+            chr_rom[16 + row] = 0xF0; // Tile 1, left half opaque
+            chr_rom[32 + row] = 0xFF; // Tile 2, solid
+        }
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.vram[33] = 1; // Background tile 1 at 8,8
+        ppu.palette[0] = 0x0F;
+        ppu.palette[1] = 0x30;
+        ppu.palette[0x11] = 0x16; // Sprite palette 0
+        ppu.palette[0x15] = 0x2A; // Sprite palette 1
+
+        let mut oam = [0xF0; 256];
+        oam[0..4].copy_from_slice(&[8, 2, 0b0010_0000, 8]); // Sprite 0 behind the background
+        oam[4..8].copy_from_slice(&[8, 2, 1, 8]); // Sprite 1 in front, lower priority
+        ppu.set_oam(oam);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        // Opaque background hides sprite 0, and sprite 0 still hides sprite 1 there
+        assert_eq!(pixel(&frame, 8, 8), palette::SYSTEM_PALLETE[0x30]);
+        // Over transparent background sprite 0 shows
+        assert_eq!(pixel(&frame, 12, 8), palette::SYSTEM_PALLETE[0x16]);
+    }
+
+    #[test]
+    fn test_flipped_sprite() {
+        let mut chr_rom = vec![0; 0x2000];
+        chr_rom[16] = 0x80; // Tile 1, only the top left pixel is set
+        let mut ppu = PPU::new(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette[0] = 0x0F;
+        ppu.palette[0x11] = 0x16;
+
+        let mut oam = [0xF0; 256];
+        oam[0..4].copy_from_slice(&[10, 1, 0b1100_0000, 20]); // Flipped both ways
+        ppu.set_oam(oam);
+
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        assert_eq!(pixel(&frame, 27, 17), palette::SYSTEM_PALLETE[0x16]);
+        assert_eq!(pixel(&frame, 20, 10), palette::SYSTEM_PALLETE[0x0F]);
+        assert_eq!(sprite_pixels(&ppu, 0)[7][7], 1);
+    }
+
+    #[test]
+    fn test_draw_tile_grid() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.palette[0] = 0x0F;
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+        let backdrop = palette::SYSTEM_PALLETE[0x0F];
+
+        draw_tile_grid(&mut frame, (255, 0, 255));
+        assert_eq!(pixel(&frame, 0, 0), (255, 0, 255));
+        assert_eq!(pixel(&frame, 8, 3), (255, 0, 255));
+        assert_eq!(pixel(&frame, 3, 16), (255, 0, 255));
+        assert_eq!(pixel(&frame, 3, 3), backdrop);
+        assert_eq!(pixel(&frame, 255, 239), backdrop);
+    }
+
+    #[test]
+    fn test_draw_attribute_overlay() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.vram[0x3c0] = 0b11_10_01_00; // bottom right, bottom left, top right, top left
+        let mut frame = Frame::new();
+        render(&ppu, &mut frame);
+
+        draw_attribute_overlay(&ppu, &mut frame);
+        let top_left = pixel(&frame, 0, 0);
+        assert_eq!(pixel(&frame, 15, 15), top_left);
+        assert_ne!(pixel(&frame, 16, 0), top_left);
+        assert_ne!(pixel(&frame, 0, 16), top_left);
+        assert_ne!(pixel(&frame, 16, 16), pixel(&frame, 16, 0));
+    }
+
+    #[test]
+    fn test_attribute_for_tile() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.vram[0x3c0] = 0b11_10_01_00; // bottom right, bottom left, top right, top left
+
+        assert_eq!(attribute_for_tile(&ppu, 0x2000, 0, 0), 0);
+        assert_eq!(attribute_for_tile(&ppu, 0x2000, 2, 0), 1);
+        assert_eq!(attribute_for_tile(&ppu, 0x2000, 0, 2), 2);
+        assert_eq!(attribute_for_tile(&ppu, 0x2000, 3, 3), 3);
+
+        // Next attribute byte covers the tiles to the right
+        assert_eq!(attribute_for_tile(&ppu, 0x2000, 4, 0), 0);
+    }
+
+    #[test]
+    fn test_bg_pallette_per_nametable() {
+        let mut ppu = PPU::new_empty_rom(); // Horizontal, 0x2000 and 0x2800 are separate tables
+        for i in 0..32 {
+            ppu.palette[i] = i as u8;
+        }
+        ppu.vram[0x3c0] = 0b01;
+        ppu.vram[0x400 + 0x3c0] = 0b10;
+
+        assert_eq!(bg_pallette(&ppu, 0x2000, 0, 0), [0, 5, 6, 7]);
+        assert_eq!(bg_pallette(&ppu, 0x2800, 0, 0), [0, 9, 10, 11]);
+        assert_eq!(bg_pallette(&ppu, 0x2400, 0, 0), [0, 5, 6, 7]); // Mirror of 0x2000
+    }
+}
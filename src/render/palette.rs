@@ -1,6 +1,9 @@
+// RGB for each of the 64 NES colours
+pub type Palette = [(u8, u8, u8); 64];
+
 #[rustfmt::skip]
 
-pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
+pub static SYSTEM_PALLETE: Palette = [
    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E),
    (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00),
    (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E), (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05),
@@ -14,4 +17,34 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
    (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0),
    (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+
+// Index of the SYSTEM_PALLETE colour closest to rgb (Euclidean distance), the first wins on ties
+pub fn nearest_color_index(rgb: (u8, u8, u8)) -> u8 {
+    let distance = |colour: &(u8, u8, u8)| {
+        let dr = colour.0 as i32 - rgb.0 as i32;
+        let dg = colour.1 as i32 - rgb.1 as i32;
+        let db = colour.2 as i32 - rgb.2 as i32;
+        dr * dr + dg * dg + db * db
+    };
+
+    let mut nearest = 0;
+    for (i, colour) in SYSTEM_PALLETE.iter().enumerate() {
+        if distance(colour) < distance(&SYSTEM_PALLETE[nearest]) {
+            nearest = i;
+        }
+    }
+    nearest as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_nearest_color_index() {
+        assert_eq!(nearest_color_index(SYSTEM_PALLETE[0x11]), 0x11);
+        assert_eq!(nearest_color_index((0x01, 0x78, 0xFE)), 0x11);
+        assert_eq!(nearest_color_index((0xFE, 0xFE, 0xFE)), 0x20); // 0x30 is also white
+    }
+}
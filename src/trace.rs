@@ -21,79 +21,35 @@ pub fn trace(cpu: &mut CPU) -> String {
     };
 
     // Get mode to determine how many to to print out
-    let mut instruction = vec![code];
+    let instruction = instruction_bytes(cpu, current_PC, opcode);
+    let mut address_string = format_operand(cpu, current_PC, opcode, &instruction);
+
+    // Trace also shows the resolved address and the value found there
+    if opcode.len > 1 {
+        let byte_val = instruction[1];
+        let suffix = match opcode.mode {
+            AddressingMode::ZeroPage => format!(" = {:02X}", mem_val),
+            AddressingMode::ZeroPage_X | AddressingMode::ZeroPage_Y =>
+                format!(" @ {:02X} = {:02X}", mem_addr, mem_val),
+            // STA ($80,X) @ 80 = 0200 = 5A
+            AddressingMode::Indirect_X =>
+                format!(" @ {:02X} = {:04X} = {:02X}", cpu.register_x.wrapping_add(byte_val), mem_addr, mem_val),
+            // LDA ($89),Y = 0300 @ 0300 = 89
+            AddressingMode::Indirect_Y =>
+                format!(" = {:04X} @ {:04X} = {:02X}", mem_addr.wrapping_sub(cpu.register_y as u16), mem_addr, mem_val),
+            AddressingMode::Absolute if opcode.code != 0x4C && opcode.code != 0x20 =>
+                format!(" = {:02X}", mem_val),
+            AddressingMode::Absolute_X | AddressingMode::Absolute_Y =>
+                format!(" @ {:04X} = {:02X}", mem_addr, mem_val),
+            AddressingMode::NoneAddressing if opcode.code == 0x6C => { // JMP indirect
+                let word_val = (instruction[2] as u16) << 8 | byte_val as u16;
+                format!(" = {:04X}", cpu.calculate_jmp_indirect_bug(word_val))
+            },
+            _ => String::new(),
+        };
+        address_string.push_str(&suffix);
+    }
 
-
-    let address_string
-     = match opcode.len {
-        1 => {
-            match opcode.code {
-                0x4A | 0x0A | 0x2A | 0x6A => format!("A"),
-                _ => format!("")
-            }
-        }
-
-        2 => {
-            let byte_val = cpu.mem_read(current_PC+1); // Value held at the next byte
-            instruction.push(byte_val);
-            
-            match opcode.mode {
-                AddressingMode::Immediate => 
-                    format!("#${:02X}", byte_val),
-                AddressingMode::ZeroPage => 
-                    format!("${:02X} = {:02X}", byte_val, mem_val),
-                AddressingMode::ZeroPage_X => 
-                    format!("${:02X},X @ {:02X} = {:02X}", byte_val, mem_addr, mem_val),
-                AddressingMode::ZeroPage_Y => 
-                    format!("${:02X},Y @ {:02X} = {:02X}", byte_val, mem_addr, mem_val),
-                // STA ($80,X) @ 80 = 0200 = 5A
-                AddressingMode::Indirect_X => 
-                    format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", byte_val, cpu.register_x.wrapping_add(byte_val), mem_addr, mem_val),
-                // LDA ($89),Y = 0300 @ 0300 = 89
-                AddressingMode::Indirect_Y => 
-                    format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", byte_val, mem_addr.wrapping_sub(cpu.register_y as u16), mem_addr, mem_val),
-                
-                AddressingMode::NoneAddressing => // Branch Instructions
-                    format!("${:04X}", (current_PC as usize + 2).wrapping_add((byte_val as i8) as usize)),
-
-                _ => panic!("Unexpected Addressing Mode at opcode.len = 2"),
-            }
-        },
-        3 => {
-            let lo_byte_val = cpu.mem_read(current_PC+1);
-            let hi_byte_val = cpu.mem_read(current_PC+2);
-            instruction.push(lo_byte_val);
-            instruction.push(hi_byte_val);
-
-            let byte_val = (hi_byte_val as u16) << 8 | (lo_byte_val as u16);
-
-            match opcode.mode {
-                AddressingMode::Absolute => {
-                    if opcode.code == 0x4C || opcode.code == 0x20 { // JMP direct
-                        format!("${:04X}", byte_val)
-                    } else {
-                        format!("${:04X} = {:02X}", byte_val, mem_val)
-                    }
-                },
-                AddressingMode::Absolute_X => format!("${:04X},X @ {:04X} = {:02X}", byte_val, mem_addr, mem_val),
-                AddressingMode::Absolute_Y => format!("${:04X},Y @ {:04X} = {:02X}", byte_val, mem_addr, mem_val),
-                AddressingMode::NoneAddressing => {
-                    if opcode.code == 0x6C {
-                        format!("(${:04X}) = {:04X}", byte_val, cpu.calculate_jmp_indirect_bug(byte_val))// JMP indirect
-                    } else {
-                        format!("(${:04X})", byte_val)
-                    }
-                },
-
-                _ => panic!("NO")
-            }
-
-        },
-        _ => {
-            format!("")
-        }
-    };
-    
     let instruction_string = instruction
         .iter()
         .map(|z| format!("{:02X}", z))
@@ -108,6 +64,61 @@ pub fn trace(cpu: &mut CPU) -> String {
 }
 
 
+// Disassemble the instruction at PC without touching CPU or bus state, e.g. "0600  A9 05     LDA #$05"
+pub fn disasm(cpu: &CPU) -> String {
+    let pc = cpu.program_counter;
+    let code = cpu.bus.peek(pc);
+    let opcode = match opcodes::lookup(code) {
+        Some(opcode) => opcode,
+        None => return format!("{:04X}  {:02X}        ???", pc, code),
+    };
+
+    let instruction = instruction_bytes(cpu, pc, opcode);
+    let address_string = format_operand(cpu, pc, opcode, &instruction);
+
+    let instruction_string = instruction
+        .iter()
+        .map(|z| format!("{:02X}", z))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    format!("{:04X}  {:8} {: >4} {}", pc, instruction_string, opcode.mnemonic, address_string).trim_end().to_string()
+}
+
+// Opcode and operand bytes of the instruction at pc, peeked so reading them has no side effects
+fn instruction_bytes(cpu: &CPU, pc: u16, opcode: &opcodes::OpCode) -> Vec<u8> {
+    (0..opcode.len as u16).map(|i| cpu.bus.peek(pc.wrapping_add(i))).collect()
+}
+
+// Operand as written in assembly, e.g. "#$05", "$0200,X" or a branch target. Shared by
+// trace and disasm, trace appends the resolved address and value
+fn format_operand(cpu: &CPU, pc: u16, opcode: &opcodes::OpCode, instruction: &[u8]) -> String {
+    let byte_val = *instruction.get(1).unwrap_or(&0);
+    let word_val = (*instruction.get(2).unwrap_or(&0) as u16) << 8 | byte_val as u16;
+
+    match (opcode.len, &opcode.mode) {
+        (1, _) => match opcode.code {
+            0x4A | 0x0A | 0x2A | 0x6A => "A".to_string(),
+            _ => String::new(),
+        },
+        (_, AddressingMode::Immediate) => format!("#${:02X}", byte_val),
+        (_, AddressingMode::ZeroPage) => format!("${:02X}", byte_val),
+        (_, AddressingMode::ZeroPage_X) => format!("${:02X},X", byte_val),
+        (_, AddressingMode::ZeroPage_Y) => format!("${:02X},Y", byte_val),
+        (_, AddressingMode::Indirect_X) => format!("(${:02X},X)", byte_val),
+        (_, AddressingMode::Indirect_Y) => format!("(${:02X}),Y", byte_val),
+        (2, AddressingMode::NoneAddressing) => // Branch Instructions
+            format!("${:04X}", pc.wrapping_add(2).wrapping_add((byte_val as i8) as u16)),
+        (_, AddressingMode::Absolute) => match (opcode.code, cpu.symbol(word_val)) {
+            (0x4C | 0x20, Some(name)) => name.to_string(), // JMP/JSR direct
+            _ => format!("${:04X}", word_val),
+        },
+        (_, AddressingMode::Absolute_X) => format!("${:04X},X", word_val),
+        (_, AddressingMode::Absolute_Y) => format!("${:04X},Y", word_val),
+        (_, AddressingMode::NoneAddressing) => format!("(${:04X})", word_val), // JMP indirect
+    }
+}
+
 
 #[cfg(test)]
 mod test {
@@ -148,6 +159,52 @@ mod test {
        );
    }
 
+   #[test]
+   fn test_format_backward_branch_at_low_pc() {
+       let mut bus = Bus::new(test_rom(), |_, _|{});
+       bus.mem_write(0x10, 0xf0); // BEQ -128
+       bus.mem_write(0x11, 0x80);
+
+       let mut cpu = CPU::new(bus);
+       cpu.program_counter = 0x10;
+       let result = trace(&mut cpu);
+       assert!(result.starts_with("0010  F0 80     BEQ $FF92 "), "{}", result);
+   }
+
+   #[test]
+   fn test_peek_disasm() {
+       let mut bus = Bus::new(test_rom(), |_, _|{});
+       bus.mem_write(0x0600, 0xa9); // LDA #$05
+       bus.mem_write(0x0601, 0x05);
+       bus.mem_write(0x0602, 0xbd); // LDA $2002,X
+       bus.mem_write(0x0603, 0x02);
+       bus.mem_write(0x0604, 0x20);
+
+       let mut cpu = CPU::new(bus);
+       cpu.program_counter = 0x0600;
+       assert_eq!(cpu.peek_disasm(), "0600  A9 05     LDA #$05");
+       assert_eq!(cpu.program_counter, 0x0600);
+
+       cpu.program_counter = 0x0602;
+       assert_eq!(cpu.peek_disasm(), "0602  BD 02 20  LDA $2002,X");
+   }
+
+   #[test]
+   fn test_format_jsr_symbol() {
+       let mut bus = Bus::new(test_rom(), |_, _|{});
+       bus.mem_write(100, 0x20); // JSR $0610
+       bus.mem_write(101, 0x10);
+       bus.mem_write(102, 0x06);
+
+       let mut cpu = CPU::new(bus);
+       cpu.program_counter = 0x64;
+       cpu.set_symbols(HashMap::from([(0x0610, "init_ppu".to_string())]));
+
+       let result = trace(&mut cpu);
+       assert!(result.starts_with("0064  20 10 06  JSR init_ppu "), "{}", result);
+       assert_eq!(cpu.peek_disasm(), "0064  20 10 06  JSR init_ppu");
+   }
+
    #[test]
    fn test_format_mem_access() {
        let mut bus = Bus::new(test_rom(), |_, _|{});